@@ -1,10 +1,14 @@
 --- Historical balances ---
 
+-- Amounts are stored as 39 digit zero-padded decimals so they order numerically as text
+
 CREATE TABLE IF NOT EXISTS brc20_prog_historical_balances (id INTEGER PRIMARY KEY, block_height INTEGER NOT NULL, wallet TEXT NOT NULL, ticker TEXT NOT NULL, amount TEXT NOT NULL);
 
 CREATE INDEX IF NOT EXISTS idx_brc20_prog_historical_balances_block_height ON brc20_prog_historical_balances (block_height);
 CREATE INDEX IF NOT EXISTS idx_brc20_prog_historical_balances_ticker ON brc20_prog_historical_balances (ticker);
 CREATE INDEX IF NOT EXISTS idx_brc20_prog_historical_balances_wallet ON brc20_prog_historical_balances (wallet);
+CREATE INDEX IF NOT EXISTS idx_brc20_prog_historical_balances_wallet_ticker_block_height ON brc20_prog_historical_balances (wallet, ticker, block_height);
+CREATE INDEX IF NOT EXISTS idx_brc20_prog_historical_balances_ticker_wallet_block_height ON brc20_prog_historical_balances (ticker, wallet, block_height);
 
 --- Current balances ---
 
@@ -14,6 +18,7 @@ CREATE INDEX IF NOT EXISTS idx_brc20_prog_current_balances_ticker ON brc20_prog_
 CREATE INDEX IF NOT EXISTS idx_brc20_prog_current_balances_wallet ON brc20_prog_current_balances (wallet);
 CREATE INDEX IF NOT EXISTS idx_brc20_prog_current_balances_block_height ON brc20_prog_current_balances (block_height);
 CREATE UNIQUE INDEX IF NOT EXISTS idx_brc20_prog_current_balances_wallet_ticker ON brc20_prog_current_balances (wallet, ticker);
+CREATE INDEX IF NOT EXISTS idx_brc20_prog_current_balances_ticker_amount ON brc20_prog_current_balances (ticker, amount);
 
 --- Block hashes ---
 
@@ -23,8 +28,26 @@ CREATE INDEX IF NOT EXISTS idx_brc20_prog_block_hashes_block_height ON brc20_pro
 
 --- brc20_prog_tickers ---
 
+-- block_height is added by migrations/0003_ticker_block_height.sql
+
 CREATE TABLE IF NOT EXISTS brc20_prog_tickers (id INTEGER PRIMARY KEY, ticker TEXT NOT NULL, ticker_hash TEXT NOT NULL, contract_address INTEGER NOT NULL);
 
 CREATE INDEX IF NOT EXISTS idx_brc20_prog_tickers_ticker ON brc20_prog_tickers (ticker);
 CREATE INDEX IF NOT EXISTS idx_brc20_prog_tickers_ticker_hash ON brc20_prog_tickers (ticker_hash);
 CREATE INDEX IF NOT EXISTS idx_brc20_prog_tickers_contract_address ON brc20_prog_tickers (contract_address);
+
+--- Metadata ---
+
+CREATE TABLE IF NOT EXISTS brc20_prog_metadata (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+
+--- Writer lease ---
+
+-- Created by writer_lease.sql, as the lease has to be held before this file runs
+
+--- Balance repairs ---
+
+-- Audit log of balances overwritten by verify --heal. reset.sql keeps it for later analysis.
+
+CREATE TABLE IF NOT EXISTS brc20_prog_balance_repairs (id INTEGER PRIMARY KEY, block_height INTEGER NOT NULL, block_hash TEXT NOT NULL, wallet TEXT NOT NULL, ticker TEXT NOT NULL, database_amount TEXT NOT NULL, node_amount TEXT NOT NULL, repaired_at INTEGER NOT NULL);
+
+CREATE INDEX IF NOT EXISTS idx_brc20_prog_balance_repairs_wallet_ticker ON brc20_prog_balance_repairs (wallet, ticker);
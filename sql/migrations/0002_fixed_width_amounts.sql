@@ -0,0 +1,9 @@
+--- Pad amounts to 39 digits so they compare and sort numerically as text ---
+
+UPDATE brc20_prog_current_balances SET amount = substr('000000000000000000000000000000000000000' || amount, -39) WHERE LENGTH(amount) < 39;
+UPDATE brc20_prog_historical_balances SET amount = substr('000000000000000000000000000000000000000' || amount, -39) WHERE LENGTH(amount) < 39;
+
+--- The holder ranking index no longer needs to order by length first ---
+
+DROP INDEX IF EXISTS idx_brc20_prog_current_balances_ticker_amount;
+CREATE INDEX IF NOT EXISTS idx_brc20_prog_current_balances_ticker_amount ON brc20_prog_current_balances (ticker, amount);
@@ -0,0 +1,7 @@
+--- Record the block a ticker was created in, so reorgs and rollbacks can remove it ---
+
+-- Tickers created before this migration keep a NULL height and are never rolled back
+
+ALTER TABLE brc20_prog_tickers ADD COLUMN block_height INTEGER;
+
+CREATE INDEX IF NOT EXISTS idx_brc20_prog_tickers_block_height ON brc20_prog_tickers (block_height);
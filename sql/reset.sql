@@ -3,3 +3,4 @@ DROP TABLE IF EXISTS brc20_prog_block_hashes;
 DROP TABLE IF EXISTS brc20_prog_current_balances;
 DROP TABLE IF EXISTS brc20_prog_historical_balances;
 DROP TABLE IF EXISTS brc20_prog_tickers;
+DROP TABLE IF EXISTS brc20_prog_metadata;
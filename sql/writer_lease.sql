@@ -0,0 +1,5 @@
+--- Writer lease ---
+
+-- At most one row, the process allowed to write. reset.sql keeps it so a reset holds on to it.
+
+CREATE TABLE IF NOT EXISTS brc20_prog_writer_lease (id INTEGER PRIMARY KEY CHECK (id = 1), holder TEXT NOT NULL, hostname TEXT NOT NULL, pid INTEGER NOT NULL, acquired_at INTEGER NOT NULL, heartbeat_at INTEGER NOT NULL);
@@ -0,0 +1,759 @@
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use brc20_prog_balance_tracker::{
+    config::{ConfigFile, LimitSection, NetworkConfig, RpcSection},
+    database::DatabaseOptions,
+    export::ExportFormat,
+    retry::RetryPolicy,
+    rpc::{RpcAuth, RpcOptions, RpcPool, read_secret},
+    status::ReadinessOptions,
+    verify::{VerifyOptions, random_heights},
+};
+use clap::{Args, Parser, Subcommand};
+
+/// Tracks BRC20 balances of the BRC2.0 module in an SQLite database.
+///
+/// Settings are read from the command line, then the environment variable shown next to each
+/// option (or a `.env` file), then the `--config` file, then the network preset. The first
+/// source that sets a value wins.
+#[derive(Parser, Debug)]
+#[command(version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    /// Options of `run` when no command is given. They can't be combined with a command, and
+    /// global options have to follow the command then.
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Args, Debug)]
+pub struct GlobalArgs {
+    /// TOML configuration file
+    #[arg(long, env = "CONFIG_FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Database to connect to [default: sqlite://balances.sqlite]
+    #[arg(long, env = "DATABASE_URL", global = true)]
+    pub db_url: Option<String>,
+
+    /// Network the database tracks, one of mainnet, signet, testnet4 or regtest
+    /// [default: mainnet]
+    #[arg(long, env = "NETWORK", global = true)]
+    pub network: Option<String>,
+
+    /// First block to index, overrides the network preset
+    #[arg(long, env = "FIRST_BLOCK", global = true)]
+    pub first_block: Option<u64>,
+
+    /// Log output format, text or json [default: text]
+    #[arg(long, env = "LOG_FORMAT", global = true)]
+    pub log_format: Option<String>,
+
+    /// Seconds without a heartbeat after which another process may take over the database
+    /// [default: 60]
+    #[arg(long, env = "WRITER_LEASE_TIMEOUT_SECS", global = true)]
+    pub writer_lease_timeout_secs: Option<u64>,
+
+    #[command(flatten)]
+    pub rpc: RpcArgs,
+
+    #[command(flatten)]
+    pub sqlite: SqliteArgs,
+}
+
+#[derive(Args, Debug)]
+#[command(next_help_heading = "RPC options")]
+pub struct RpcArgs {
+    /// BRC2.0 RPC server to connect to, required by commands that talk to the node. Can be
+    /// repeated to fail over to the next server, the environment variable takes a comma
+    /// separated list
+    #[arg(long, env = "RPC_URL", value_delimiter = ',', global = true)]
+    pub rpc_url: Vec<String>,
+
+    /// Index a block only if a majority of the RPC servers return the same hash for it
+    #[arg(long, env = "RPC_QUORUM", global = true)]
+    pub rpc_quorum: bool,
+
+    /// Authentication mode, one of none, basic or bearer. Inferred from the credentials that
+    /// are set if omitted
+    #[arg(long, env = "RPC_AUTH", global = true)]
+    pub rpc_auth: Option<String>,
+
+    /// Username for basic authentication
+    #[arg(long, env = "RPC_USER", global = true)]
+    pub rpc_user: Option<String>,
+
+    /// Password for basic authentication
+    #[arg(long, env = "RPC_PASSWORD", hide_env_values = true, global = true)]
+    pub rpc_password: Option<String>,
+
+    /// File holding the password for basic authentication
+    #[arg(long, env = "RPC_PASSWORD_FILE", global = true)]
+    pub rpc_password_file: Option<PathBuf>,
+
+    /// Token for bearer authentication
+    #[arg(long, env = "RPC_TOKEN", hide_env_values = true, global = true)]
+    pub rpc_token: Option<String>,
+
+    /// File holding the token for bearer authentication
+    #[arg(long, env = "RPC_TOKEN_FILE", global = true)]
+    pub rpc_token_file: Option<PathBuf>,
+
+    /// Extra header sent with every request as `Name: value`, can be repeated. The
+    /// environment variable takes a comma separated list
+    #[arg(
+        long,
+        env = "RPC_HEADERS",
+        value_delimiter = ',',
+        hide_env_values = true,
+        global = true
+    )]
+    pub rpc_header: Vec<String>,
+
+    /// Request timeout in seconds [default: 60]
+    #[arg(long, env = "RPC_TIMEOUT_SECS", global = true)]
+    pub rpc_timeout_secs: Option<u64>,
+
+    /// Largest accepted response in bytes [default: 268435456]
+    #[arg(long, env = "RPC_MAX_RESPONSE_SIZE", global = true)]
+    pub rpc_max_response_size: Option<u32>,
+
+    /// PEM file with the certificate authorities to trust instead of the system ones
+    #[arg(long, env = "RPC_CA_CERT", global = true)]
+    pub rpc_ca_cert: Option<PathBuf>,
+
+    /// Attempts per request including the first one, 1 disables retries [default: 3]
+    #[arg(long, env = "RPC_RETRY_ATTEMPTS", global = true)]
+    pub rpc_retry_attempts: Option<u32>,
+
+    /// Delay before the first retry in milliseconds, doubled on every retry [default: 500]
+    #[arg(long, env = "RPC_RETRY_INITIAL_BACKOFF_MS", global = true)]
+    pub rpc_retry_initial_backoff_ms: Option<u64>,
+
+    /// Longest delay between retries in milliseconds [default: 30000]
+    #[arg(long, env = "RPC_RETRY_MAX_BACKOFF_MS", global = true)]
+    pub rpc_retry_max_backoff_ms: Option<u64>,
+
+    /// Fraction of each delay that is randomized, between 0 and 1 [default: 0.2]
+    #[arg(long, env = "RPC_RETRY_JITTER", global = true)]
+    pub rpc_retry_jitter: Option<f64>,
+
+    /// Failed requests in a row that pause all requests, 0 disables the circuit breaker
+    /// [default: 5]
+    #[arg(long, env = "RPC_CIRCUIT_BREAKER_THRESHOLD", global = true)]
+    pub rpc_circuit_breaker_threshold: Option<u32>,
+
+    /// How long requests are paused once the circuit breaker opens, in seconds [default: 30]
+    #[arg(long, env = "RPC_CIRCUIT_BREAKER_COOLDOWN_SECS", global = true)]
+    pub rpc_circuit_breaker_cooldown_secs: Option<u64>,
+
+    /// Requests per second to each RPC server, unlimited if unset. Limits per method class
+    /// and per server can be set in the config file
+    #[arg(long, env = "RPC_RATE_LIMIT", global = true)]
+    pub rpc_rate_limit: Option<f64>,
+
+    /// Requests in flight at once to each RPC server, unlimited if unset
+    #[arg(long, env = "RPC_MAX_IN_FLIGHT", global = true)]
+    pub rpc_max_in_flight: Option<usize>,
+
+    /// Most requests sent in one JSON-RPC batch, 1 disables batching [default: 100]
+    #[arg(long, env = "RPC_BATCH_SIZE", global = true)]
+    pub rpc_batch_size: Option<usize>,
+}
+
+impl RpcArgs {
+    pub fn resolve(self, file: &RpcSection) -> Result<RpcSettings, String> {
+        let user = self.rpc_user.or(file.user.clone());
+        let password = match self.rpc_password.or(file.password.clone()) {
+            Some(password) => Some(password),
+            None => self
+                .rpc_password_file
+                .or(file.password_file.clone())
+                .map(|path| read_secret(&path))
+                .transpose()?,
+        };
+        let token = match self.rpc_token.or(file.token.clone()) {
+            Some(token) => Some(token),
+            None => self
+                .rpc_token_file
+                .or(file.token_file.clone())
+                .map(|path| read_secret(&path))
+                .transpose()?,
+        };
+
+        let mode = self.rpc_auth.or(file.auth.clone()).unwrap_or_else(|| {
+            if token.is_some() {
+                "bearer".to_string()
+            } else if user.is_some() || password.is_some() {
+                "basic".to_string()
+            } else {
+                "none".to_string()
+            }
+        });
+        let auth = match mode.as_str() {
+            "none" => RpcAuth::None,
+            "basic" => match (user, password) {
+                (Some(user), Some(password)) => RpcAuth::Basic { user, password },
+                _ => return Err("Basic RPC authentication needs a user and a password".to_string()),
+            },
+            "bearer" => RpcAuth::Bearer(
+                token.ok_or("Bearer RPC authentication needs a token or a token file")?,
+            ),
+            _ => {
+                return Err(format!(
+                    "Invalid RPC auth mode {}, expected none, basic or bearer",
+                    mode
+                ));
+            }
+        };
+
+        let mut headers: Vec<(String, String)> = file
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        for header in self.rpc_header {
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid RPC header {:?}, expected Name: value", header))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let mut limits = file.limits.to_limits()?;
+        let total = LimitSection {
+            rate: self.rpc_rate_limit,
+            burst: None,
+            max_in_flight: self.rpc_max_in_flight,
+        }
+        .to_limit()?;
+        limits.total = total.or(limits.total);
+
+        let urls = if !self.rpc_url.is_empty() {
+            self.rpc_url
+        } else {
+            match (&file.url, file.urls.is_empty()) {
+                (Some(_), false) => {
+                    return Err("Set either rpc.url or rpc.urls in the config file".to_string());
+                }
+                (Some(url), true) => vec![url.clone()],
+                (None, _) => file.urls.clone(),
+            }
+        };
+
+        let mut endpoint_limits = BTreeMap::new();
+        for (url, section) in &file.endpoint_limits {
+            if !urls.contains(url) {
+                return Err(format!(
+                    "rpc.endpoint_limits has limits for {}, which is not a configured RPC URL",
+                    url
+                ));
+            }
+            endpoint_limits.insert(url.clone(), section.to_limits()?.or(limits));
+        }
+
+        let defaults = RpcOptions::default();
+        let retry = RetryPolicy {
+            max_attempts: self
+                .rpc_retry_attempts
+                .or(file.retry_attempts)
+                .unwrap_or(defaults.retry.max_attempts),
+            initial_backoff: self
+                .rpc_retry_initial_backoff_ms
+                .or(file.retry_initial_backoff_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.retry.initial_backoff),
+            max_backoff: self
+                .rpc_retry_max_backoff_ms
+                .or(file.retry_max_backoff_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.retry.max_backoff),
+            jitter: self
+                .rpc_retry_jitter
+                .or(file.retry_jitter)
+                .unwrap_or(defaults.retry.jitter),
+        };
+        if retry.max_attempts == 0 {
+            return Err("RPC retry attempts must be at least 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&retry.jitter) {
+            return Err(format!(
+                "RPC retry jitter must be between 0 and 1, got {}",
+                retry.jitter
+            ));
+        }
+
+        Ok(RpcSettings {
+            urls,
+            quorum: self.rpc_quorum || file.quorum.unwrap_or(false),
+            options: RpcOptions {
+                auth,
+                headers,
+                request_timeout: self
+                    .rpc_timeout_secs
+                    .or(file.request_timeout_secs)
+                    .map(Duration::from_secs)
+                    .unwrap_or(defaults.request_timeout),
+                max_response_size: self
+                    .rpc_max_response_size
+                    .or(file.max_response_size)
+                    .unwrap_or(defaults.max_response_size),
+                ca_certificate: self.rpc_ca_cert.or(file.ca_certificate.clone()),
+                retry,
+                circuit_breaker_threshold: self
+                    .rpc_circuit_breaker_threshold
+                    .or(file.circuit_breaker_threshold)
+                    .unwrap_or(defaults.circuit_breaker_threshold),
+                circuit_breaker_cooldown: self
+                    .rpc_circuit_breaker_cooldown_secs
+                    .or(file.circuit_breaker_cooldown_secs)
+                    .map(Duration::from_secs)
+                    .unwrap_or(defaults.circuit_breaker_cooldown),
+                limits,
+                endpoint_limits,
+                batch_size: self
+                    .rpc_batch_size
+                    .or(file.batch_size)
+                    .unwrap_or(defaults.batch_size),
+            },
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+#[command(next_help_heading = "SQLite options")]
+pub struct SqliteArgs {
+    /// Journal mode of the database [default: wal]
+    #[arg(long, env = "SQLITE_JOURNAL_MODE", global = true)]
+    pub sqlite_journal_mode: Option<String>,
+
+    /// Synchronous level, one of off, normal, full or extra [default: normal]
+    #[arg(long, env = "SQLITE_SYNCHRONOUS", global = true)]
+    pub sqlite_synchronous: Option<String>,
+
+    /// Page cache size, negative values are in KiB [default: -64000]
+    #[arg(
+        long,
+        env = "SQLITE_CACHE_SIZE",
+        allow_negative_numbers = true,
+        global = true
+    )]
+    pub sqlite_cache_size: Option<i64>,
+
+    /// How long to wait for a locked database, in milliseconds [default: 5000]
+    #[arg(long, env = "SQLITE_BUSY_TIMEOUT_MS", global = true)]
+    pub sqlite_busy_timeout_ms: Option<u64>,
+
+    /// Maximum number of bytes to memory-map, 0 disables mmap [default: 268435456]
+    #[arg(long, env = "SQLITE_MMAP_SIZE", global = true)]
+    pub sqlite_mmap_size: Option<u64>,
+
+    /// Size of the read-only query pool [default: 4]
+    #[arg(long, env = "SQLITE_READ_CONNECTIONS", global = true)]
+    pub sqlite_read_connections: Option<u32>,
+}
+
+/// Settings shared by every command, after merging flags, environment, config file and
+/// network preset
+pub struct Settings {
+    pub db_url: String,
+    pub db_options: DatabaseOptions,
+    pub rpc: RpcSettings,
+    pub network: NetworkConfig,
+    pub log_format: String,
+    pub writer_lease_timeout: Duration,
+}
+
+pub struct RpcSettings {
+    /// Empty unless configured, only commands that talk to the node need one
+    pub urls: Vec<String>,
+    pub quorum: bool,
+    pub options: RpcOptions,
+}
+
+impl RpcSettings {
+    /// Panics if no URL is configured
+    pub fn client(&self) -> RpcPool {
+        if self.urls.is_empty() {
+            panic!(
+                "RPC URL is not set, pass --rpc-url, set RPC_URL or set rpc.url in the config file"
+            );
+        }
+        RpcPool::new(&self.urls, &self.options, self.quorum)
+            .unwrap_or_else(|err| panic!("Failed to create RPC client: {}", err))
+    }
+}
+
+impl GlobalArgs {
+    pub fn config_file(&self) -> Result<ConfigFile, String> {
+        match &self.config {
+            Some(path) => ConfigFile::load(path),
+            None => Ok(ConfigFile::default()),
+        }
+    }
+
+    pub fn resolve(self, file: &ConfigFile) -> Result<Settings, String> {
+        let network_name = self
+            .network
+            .or(file.network.name.clone())
+            .unwrap_or_else(|| "mainnet".to_string());
+        let network = NetworkConfig::resolve(&network_name, &file.network, self.first_block)?;
+
+        let rpc = self.rpc.resolve(&file.rpc)?;
+
+        let database = &file.database;
+        let defaults = DatabaseOptions::default();
+        let sqlite = self.sqlite;
+        let db_options = DatabaseOptions {
+            journal_mode: match sqlite.sqlite_journal_mode.or(database.journal_mode.clone()) {
+                Some(mode) => mode
+                    .parse()
+                    .map_err(|_| format!("Invalid journal mode {}", mode))?,
+                None => defaults.journal_mode,
+            },
+            synchronous: match sqlite.sqlite_synchronous.or(database.synchronous.clone()) {
+                Some(level) => level
+                    .parse()
+                    .map_err(|_| format!("Invalid synchronous level {}", level))?,
+                None => defaults.synchronous,
+            },
+            cache_size: sqlite
+                .sqlite_cache_size
+                .or(database.cache_size)
+                .unwrap_or(defaults.cache_size),
+            busy_timeout: sqlite
+                .sqlite_busy_timeout_ms
+                .or(database.busy_timeout_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.busy_timeout),
+            mmap_size: sqlite
+                .sqlite_mmap_size
+                .or(database.mmap_size)
+                .unwrap_or(defaults.mmap_size),
+            read_connections: sqlite
+                .sqlite_read_connections
+                .or(database.read_connections)
+                .unwrap_or(defaults.read_connections),
+        };
+
+        let log_format = self
+            .log_format
+            .or(file.log.format.clone())
+            .unwrap_or_else(|| "text".to_string());
+        if log_format != "text" && log_format != "json" {
+            return Err(format!(
+                "Invalid log format {}, expected text or json",
+                log_format
+            ));
+        }
+
+        Ok(Settings {
+            db_url: self
+                .db_url
+                .or(database.url.clone())
+                .unwrap_or_else(|| "sqlite://balances.sqlite".to_string()),
+            db_options,
+            rpc,
+            network,
+            log_format,
+            writer_lease_timeout: Duration::from_secs(
+                self.writer_lease_timeout_secs
+                    .or(database.writer_lease_timeout_secs)
+                    .unwrap_or(60)
+                    .max(1),
+            ),
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Index new blocks as they arrive, the default when no command is given
+    Run(RunArgs),
+    /// Compare a random sample or all balances against the node at the indexed height and exit
+    Verify(VerifyArgs),
+    /// Drop all tracked data and recreate the tables
+    Reset,
+    /// Revert balances, block hashes and tickers to a past height, indexing resumes from there
+    Rollback(RollbackArgs),
+    /// Print the indexed height, node tip and database metadata
+    Status,
+    /// Query balances from the database
+    #[command(subcommand)]
+    Query(QueryCommand),
+    /// Export non-zero balances to a file
+    Export(ExportArgs),
+}
+
+#[derive(Args, Debug)]
+#[command(next_help_heading = "Run options")]
+pub struct RunArgs {
+    /// Serve the JSON-RPC query API on this address
+    #[arg(long, env = "SERVER_ADDR")]
+    pub server_addr: Option<String>,
+
+    /// Serve metrics, /healthz and /readyz on this address
+    #[arg(long, env = "STATUS_ADDR")]
+    pub status_addr: Option<String>,
+
+    /// Largest acceptable lag behind the node tip for /readyz [default: 2]
+    #[arg(long, env = "READY_MAX_LAG")]
+    pub ready_max_lag: Option<u64>,
+
+    /// How long /readyz tolerates no new block while behind the tip, in seconds [default: 600]
+    #[arg(long, env = "READY_MAX_BLOCK_AGE_SECS")]
+    pub ready_max_block_age_secs: Option<u64>,
+
+    /// How long to wait for the current block to finish after SIGINT or SIGTERM before
+    /// rolling it back, in seconds [default: 30]
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+
+    /// Verify balances against the node before indexing
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Verify a sample of balances every this many seconds while indexing, off by default
+    #[arg(long, env = "VERIFY_INTERVAL_SECS")]
+    pub verify_interval_secs: Option<u64>,
+
+    /// Balances checked by each background verification [default: 100]
+    #[arg(long, env = "VERIFY_SAMPLE")]
+    pub verify_sample: Option<u32>,
+}
+
+impl RunArgs {
+    pub fn server_addr(&self, file: &ConfigFile) -> Option<String> {
+        self.server_addr.clone().or(file.server.addr.clone())
+    }
+
+    pub fn status_addr(&self, file: &ConfigFile) -> Option<String> {
+        self.status_addr.clone().or(file.status.addr.clone())
+    }
+
+    pub fn readiness(&self, file: &ConfigFile) -> ReadinessOptions {
+        let mut readiness = ReadinessOptions::default();
+        if let Some(max_lag) = self.ready_max_lag.or(file.status.ready_max_lag) {
+            readiness.max_lag = max_lag;
+        }
+        if let Some(max_block_age) = self
+            .ready_max_block_age_secs
+            .or(file.status.ready_max_block_age_secs)
+        {
+            readiness.max_block_age = Duration::from_secs(max_block_age);
+        }
+        readiness
+    }
+
+    /// Interval and options of background verification, `None` if it is off
+    pub fn background_verification(&self, file: &ConfigFile) -> Option<(Duration, VerifyOptions)> {
+        let interval = self
+            .verify_interval_secs
+            .or(file.verify.interval_secs)
+            .filter(|secs| *secs > 0)?;
+        Some((
+            Duration::from_secs(interval),
+            VerifyOptions {
+                sample: Some(self.verify_sample.or(file.verify.sample).unwrap_or(100)),
+                concurrency: file.verify.concurrency.unwrap_or(1),
+                ..Default::default()
+            },
+        ))
+    }
+
+    pub fn shutdown_timeout(&self, file: &ConfigFile) -> Duration {
+        Duration::from_secs(
+            self.shutdown_timeout_secs
+                .or(file.shutdown.timeout_secs)
+                .unwrap_or(30),
+        )
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// Check every selected balance instead of a random sample
+    #[arg(long, conflicts_with = "sample")]
+    pub all: bool,
+    /// Number of random balances to check [default: 1000]
+    #[arg(long)]
+    pub sample: Option<u32>,
+    /// Comma separated tickers to check, all tickers if omitted
+    #[arg(long, value_delimiter = ',')]
+    pub tickers: Vec<String>,
+    /// Comma separated wallets to check, all wallets if omitted
+    #[arg(long, value_delimiter = ',')]
+    pub wallets: Vec<String>,
+    /// Batches of balance calls sent to the node at once
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+    /// Comma separated past heights to verify at instead of the indexed height, balances are
+    /// reconstructed from history and the node has to answer calls for old blocks, which
+    /// brc20-prog doesn't
+    #[arg(long, value_delimiter = ',', conflicts_with = "random_heights")]
+    pub heights: Vec<u64>,
+    /// Number of random heights between the first and the indexed block to verify at
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub random_heights: Option<u32>,
+    /// Overwrite mismatching balances with the node's value and record them in the
+    /// brc20_prog_balance_repairs table. Takes the writer lease, so stop the tracker first
+    #[arg(long, conflicts_with_all = ["heights", "random_heights"])]
+    pub heal: bool,
+    /// Write a JSON report to this file
+    #[arg(long)]
+    pub json_report: Option<PathBuf>,
+    /// Write a JUnit XML report to this file
+    #[arg(long)]
+    pub junit_report: Option<PathBuf>,
+}
+
+impl VerifyArgs {
+    /// One run per height to verify at, a single run at the indexed height by default. Fails
+    /// if random heights are asked for but nothing is indexed yet.
+    pub fn options(
+        &self,
+        first_block: u64,
+        indexed_height: u64,
+    ) -> Result<Vec<VerifyOptions>, String> {
+        let defaults = VerifyOptions::default();
+        let options = VerifyOptions {
+            sample: if self.all {
+                None
+            } else {
+                self.sample.or(defaults.sample)
+            },
+            tickers: self.tickers.clone(),
+            wallets: self.wallets.iter().map(|w| w.to_lowercase()).collect(),
+            concurrency: self.concurrency,
+            height: None,
+        };
+        let heights = match self.random_heights {
+            Some(count) => random_heights(first_block, indexed_height, count),
+            None if self.heights.is_empty() => return Ok(vec![options]),
+            None => self.heights.clone(),
+        };
+        if heights.is_empty() {
+            return Err(format!(
+                "No indexed blocks between {} and {} to verify at",
+                first_block, indexed_height
+            ));
+        }
+        Ok(heights
+            .into_iter()
+            .map(|height| VerifyOptions {
+                height: Some(height),
+                ..options.clone()
+            })
+            .collect())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RollbackArgs {
+    /// Last block to keep
+    #[arg(long)]
+    pub to: u64,
+    /// Skip the confirmation prompt
+    #[arg(long, short)]
+    pub yes: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum QueryCommand {
+    /// Balance of a wallet for a ticker
+    Balance {
+        wallet: String,
+        ticker: String,
+        /// Read the balance right after this block instead of the current one
+        #[arg(long)]
+        height: Option<u64>,
+    },
+    /// All balances of a wallet
+    WalletBalances {
+        wallet: String,
+        /// Read the balances right after this block instead of the current ones
+        #[arg(long)]
+        height: Option<u64>,
+    },
+    /// All wallets with a non-zero balance of a ticker right after a block
+    Holders { ticker: String, height: u64 },
+    /// Holders of a ticker ranked by balance
+    TopHolders {
+        ticker: String,
+        /// Number of holders to return
+        #[arg(long, default_value_t = 100)]
+        limit: u32,
+        /// Rank holders right after this block instead of the current one
+        #[arg(long)]
+        height: Option<u64>,
+        /// Continue after the cursor printed by the previous page
+        #[arg(long)]
+        cursor: Option<String>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// csv, ndjson or parquet
+    pub format: ExportFormat,
+    pub path: PathBuf,
+    /// Comma separated tickers to export, all tickers if omitted
+    #[arg(long, value_delimiter = ',')]
+    pub tickers: Vec<String>,
+    /// Export balances right after this block instead of the current ones
+    #[arg(long)]
+    pub height: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verify_args(args: &[&str]) -> Result<VerifyArgs, clap::Error> {
+        let cli = Cli::try_parse_from(["tracker", "verify"].iter().chain(args))?;
+        match cli.command {
+            Some(Command::Verify(args)) => Ok(args),
+            command => panic!("Parsed {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_run_args_without_command() {
+        let cli = Cli::try_parse_from(["tracker", "--verify"]).unwrap();
+        assert!(cli.run.verify);
+        assert!(cli.command.is_none());
+        // They would be ignored next to a command
+        assert!(Cli::try_parse_from(["tracker", "--verify", "status"]).is_err());
+        assert!(Cli::try_parse_from(["tracker", "--verify", "run"]).is_err());
+        // Global options go after the command
+        assert!(
+            Cli::try_parse_from(["tracker", "status", "--db-url", "sqlite://a.sqlite"]).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_verify_heights() {
+        let options = verify_args(&[]).unwrap().options(10, 20).unwrap();
+        assert_eq!(options.len(), 1);
+        assert_eq!(options[0].height, None);
+
+        let options = verify_args(&["--heights", "12,15"])
+            .unwrap()
+            .options(10, 20)
+            .unwrap();
+        assert_eq!(
+            options.iter().map(|o| o.height).collect::<Vec<_>>(),
+            vec![Some(12), Some(15)]
+        );
+
+        let args = verify_args(&["--random-heights", "3"]).unwrap();
+        assert_eq!(args.options(10, 20).unwrap().len(), 3);
+        // Nothing indexed yet
+        assert!(args.options(10, 9).is_err());
+        assert!(verify_args(&["--random-heights", "0"]).is_err());
+    }
+}
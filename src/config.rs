@@ -0,0 +1,390 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use alloy_primitives::Address;
+use serde::Deserialize;
+
+use crate::limit::{EndpointLimits, Limit};
+
+/// Chain id of the BRC2.0 module on mainnet, `BRC20` in ASCII
+pub const MAINNET_CHAIN_ID: u64 = 0x4252433230;
+/// Chain id of the BRC2.0 module on every test network, `BRC20s` in ASCII
+pub const TESTNET_CHAIN_ID: u64 = 0x425243323073;
+
+/// The BRC20 controller contract, deployed at the same address on every network
+const CONTROLLER_ADDRESS: &str = "0xc54dd4581af2dbf18e4d90840226756e9d2b3cdb";
+
+/// Networks the tracker knows, other names are rejected at startup. The mainnet and signet
+/// values are the ones the tracker has always used for these networks. brc20-prog uses the
+/// same chain id on every test network, but there is no known deployment to take a first
+/// block from on testnet4 and regtest, so there it has to be configured.
+pub const NETWORK_PRESETS: &[NetworkPreset] = &[
+    NetworkPreset {
+        name: "mainnet",
+        first_block: Some(912690),
+        controller_address: CONTROLLER_ADDRESS,
+        chain_id: MAINNET_CHAIN_ID,
+    },
+    NetworkPreset {
+        name: "signet",
+        first_block: Some(230000),
+        controller_address: CONTROLLER_ADDRESS,
+        chain_id: TESTNET_CHAIN_ID,
+    },
+    NetworkPreset {
+        name: "testnet4",
+        first_block: None,
+        controller_address: CONTROLLER_ADDRESS,
+        chain_id: TESTNET_CHAIN_ID,
+    },
+    NetworkPreset {
+        name: "regtest",
+        first_block: None,
+        controller_address: CONTROLLER_ADDRESS,
+        chain_id: TESTNET_CHAIN_ID,
+    },
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkPreset {
+    pub name: &'static str,
+    pub first_block: Option<u64>,
+    pub controller_address: &'static str,
+    pub chain_id: u64,
+}
+
+/// The network a database tracks, a preset with optional overrides
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkConfig {
+    pub name: String,
+    pub first_block: u64,
+    /// Lowercase hex with a `0x` prefix
+    pub controller_address: String,
+    pub chain_id: u64,
+}
+
+impl NetworkConfig {
+    /// Starts from the preset called `name` and applies the overrides of a `[network]`
+    /// section, then `first_block`. Fails on unknown names instead of guessing a first block,
+    /// if the preset leaves the first block to be configured and nothing sets it, and on
+    /// block 0, which leaves nothing before the first block for an empty database to be at.
+    pub fn resolve(
+        name: &str,
+        section: &NetworkSection,
+        first_block: Option<u64>,
+    ) -> Result<Self, String> {
+        let preset = NETWORK_PRESETS
+            .iter()
+            .find(|preset| preset.name == name)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown network {}, expected one of {}",
+                    name,
+                    NETWORK_PRESETS
+                        .iter()
+                        .map(|preset| preset.name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+        let controller_address = match &section.controller_address {
+            Some(controller_address) => controller_address
+                .parse::<Address>()
+                .map_err(|_| format!("Invalid controller address {}", controller_address))?
+                .to_string()
+                .to_lowercase(),
+            None => preset.controller_address.to_string(),
+        };
+        let first_block = first_block
+            .or(section.first_block)
+            .or(preset.first_block)
+            .ok_or_else(|| {
+                format!(
+                    "Network {} has no default first block, pass --first-block, set FIRST_BLOCK or set network.first_block in the config file",
+                    name
+                )
+            })?;
+        if first_block == 0 {
+            return Err("The first block has to be at least 1".to_string());
+        }
+        Ok(NetworkConfig {
+            name: preset.name.to_string(),
+            first_block,
+            controller_address,
+            chain_id: section.chain_id.unwrap_or(preset.chain_id),
+        })
+    }
+}
+
+/// Contents of the TOML configuration file. Every setting is optional, unknown keys are
+/// rejected so typos don't silently fall back to defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub network: NetworkSection,
+    pub rpc: RpcSection,
+    pub database: DatabaseSection,
+    pub server: ServerSection,
+    pub status: StatusSection,
+    pub log: LogSection,
+    pub shutdown: ShutdownSection,
+    pub verify: VerifySection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSection {
+    /// One of the presets in `NETWORK_PRESETS`
+    pub name: Option<String>,
+    pub first_block: Option<u64>,
+    pub controller_address: Option<String>,
+    pub chain_id: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcSection {
+    pub url: Option<String>,
+    /// Several endpoints instead of `url`, tried in order
+    pub urls: Vec<String>,
+    /// Index a block only if a majority of the endpoints agree on its hash
+    pub quorum: Option<bool>,
+    /// none, basic or bearer, inferred from the credentials that are set if omitted
+    pub auth: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub headers: BTreeMap<String, String>,
+    pub request_timeout_secs: Option<u64>,
+    pub max_response_size: Option<u32>,
+    pub ca_certificate: Option<PathBuf>,
+    pub retry_attempts: Option<u32>,
+    pub retry_initial_backoff_ms: Option<u64>,
+    pub retry_max_backoff_ms: Option<u64>,
+    pub retry_jitter: Option<f64>,
+    pub circuit_breaker_threshold: Option<u32>,
+    pub circuit_breaker_cooldown_secs: Option<u64>,
+    pub batch_size: Option<usize>,
+    /// Limits of every endpoint
+    pub limits: LimitsSection,
+    /// Limits of single endpoints by URL, unset values fall back to `limits`
+    pub endpoint_limits: BTreeMap<String, LimitsSection>,
+}
+
+/// `[rpc.limits]`, the top level applies to all requests to an endpoint and the tables below
+/// to one method class
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub rate: Option<f64>,
+    pub burst: Option<u32>,
+    pub max_in_flight: Option<usize>,
+    pub logs: LimitSection,
+    pub blocks: LimitSection,
+    pub call: LimitSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSection {
+    pub rate: Option<f64>,
+    pub burst: Option<u32>,
+    pub max_in_flight: Option<usize>,
+}
+
+impl LimitsSection {
+    pub fn to_limits(&self) -> Result<EndpointLimits, String> {
+        let total = LimitSection {
+            rate: self.rate,
+            burst: self.burst,
+            max_in_flight: self.max_in_flight,
+        };
+        Ok(EndpointLimits {
+            total: total.to_limit()?,
+            logs: self.logs.to_limit()?,
+            blocks: self.blocks.to_limit()?,
+            call: self.call.to_limit()?,
+        })
+    }
+}
+
+impl LimitSection {
+    pub fn to_limit(&self) -> Result<Limit, String> {
+        if let Some(rate) = self.rate
+            && (rate.is_nan() || rate <= 0.0)
+        {
+            return Err(format!("RPC rate limit must be positive, got {}", rate));
+        }
+        if self.max_in_flight == Some(0) {
+            return Err("RPC max in flight must be at least 1".to_string());
+        }
+        Ok(Limit {
+            rate: self.rate,
+            burst: self.burst,
+            max_in_flight: self.max_in_flight,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSection {
+    pub url: Option<String>,
+    pub journal_mode: Option<String>,
+    pub synchronous: Option<String>,
+    pub cache_size: Option<i64>,
+    pub busy_timeout_ms: Option<u64>,
+    pub mmap_size: Option<u64>,
+    pub read_connections: Option<u32>,
+    pub writer_lease_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub addr: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusSection {
+    pub addr: Option<String>,
+    pub ready_max_lag: Option<u64>,
+    pub ready_max_block_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSection {
+    pub timeout_secs: Option<u64>,
+}
+
+/// Background verification while indexing, off unless `interval_secs` is set
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerifySection {
+    pub interval_secs: Option<u64>,
+    pub sample: Option<u32>,
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    pub format: Option<String>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        Self::parse(&contents).map_err(|err| format!("Invalid {}: {}", path.display(), err))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file() {
+        let config = ConfigFile::parse(
+            r#"
+            [network]
+            name = "signet"
+            first_block = 240000
+
+            [rpc]
+            url = "http://localhost:18545"
+            token_file = "/run/secrets/rpc_token"
+
+            [rpc.headers]
+            X-Api-Key = "key"
+
+            [rpc.limits]
+            max_in_flight = 16
+
+            [rpc.limits.logs]
+            rate = 2.5
+
+            [database]
+            journal_mode = "wal"
+            cache_size = -2000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rpc.url.as_deref(), Some("http://localhost:18545"));
+        assert_eq!(config.rpc.user, None);
+        assert_eq!(
+            config.rpc.token_file,
+            Some(PathBuf::from("/run/secrets/rpc_token"))
+        );
+        assert_eq!(config.rpc.headers["X-Api-Key"], "key");
+        let limits = config.rpc.limits.to_limits().unwrap();
+        assert_eq!(limits.total.max_in_flight, Some(16));
+        assert_eq!(limits.logs.rate, Some(2.5));
+        assert_eq!(limits.call, Limit::default());
+        assert_eq!(config.database.cache_size, Some(-2000));
+
+        let network = NetworkConfig::resolve(
+            config.network.name.as_deref().unwrap(),
+            &config.network,
+            None,
+        )
+        .unwrap();
+        assert_eq!(network.first_block, 240000);
+        assert_eq!(network.chain_id, TESTNET_CHAIN_ID);
+        assert_eq!(network.controller_address, CONTROLLER_ADDRESS);
+
+        assert!(ConfigFile::parse("[rpc]\nuser = \"user\"\npasword = \"typo\"").is_err());
+        assert!(ConfigFile::parse("[network]\nfirst_block = \"912690\"").is_err());
+        let none = NetworkSection::default();
+        assert!(NetworkConfig::resolve("testnet", &none, None).is_err());
+        // Nothing to take a first block from on regtest, it has to be configured
+        assert!(NetworkConfig::resolve("regtest", &none, None).is_err());
+        let network = NetworkConfig::resolve("regtest", &none, Some(100)).unwrap();
+        assert_eq!(
+            (network.first_block, network.chain_id),
+            (100, TESTNET_CHAIN_ID)
+        );
+        assert!(NetworkConfig::resolve("regtest", &none, Some(0)).is_err());
+        let network = NetworkConfig::resolve(
+            "regtest",
+            &NetworkSection {
+                chain_id: Some(0x1234),
+                ..Default::default()
+            },
+            Some(100),
+        )
+        .unwrap();
+        assert_eq!((network.first_block, network.chain_id), (100, 0x1234));
+        assert!(
+            ConfigFile::parse("[rpc.limits.call]\nrate = 0")
+                .unwrap()
+                .rpc
+                .limits
+                .to_limits()
+                .is_err()
+        );
+
+        assert!(
+            NetworkConfig::resolve(
+                "mainnet",
+                &NetworkSection {
+                    controller_address: Some("0x1234".to_string()),
+                    ..Default::default()
+                },
+                None
+            )
+            .is_err()
+        );
+    }
+}
@@ -1,29 +1,785 @@
+use std::{str::FromStr, time::Duration};
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use futures::{Stream, StreamExt, future::BoxFuture};
 use rust_embed::Embed;
-use sqlx::{Row, Sqlite, SqlitePool, migrate::MigrateDatabase};
+use sqlx::{
+    Row, Sqlite, SqliteConnection, SqlitePool, Transaction,
+    migrate::MigrateDatabase,
+    query::Query,
+    sqlite::{
+        SqliteArguments, SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow,
+        SqliteSynchronous,
+    },
+};
+use tracing::{debug, info, warn};
+
+use crate::metrics::{METRICS, unix_time};
 
 #[derive(Embed)]
 #[folder = "sql"]
 struct Sql;
 
+/// Version of the table layout written by this build, stored in the metadata table
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Steps run before a migration in its transaction
+type MigrationStep<T> = for<'c> fn(&'c mut SqliteConnection) -> BoxFuture<'c, T>;
+
+/// Brings the schema from the previous version to `version`
+struct Migration {
+    version: u32,
+    file: &'static str,
+    /// Refuses to migrate data the migration would corrupt
+    validate: Option<MigrationStep<Result<(), String>>>,
+    /// True if the changes are already there, for statements that can't be repeated
+    applied: Option<MigrationStep<bool>>,
+}
+
+/// Migrations from the previous schema version, databases without a version are at version 1
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        file: "migrations/0002_fixed_width_amounts.sql",
+        validate: Some(validate_amounts),
+        applied: None,
+    },
+    Migration {
+        version: 3,
+        file: "migrations/0003_ticker_block_height.sql",
+        validate: None,
+        applied: Some(has_ticker_block_height),
+    },
+];
+
+/// Amounts are stored as zero-padded decimals so SQL can compare and order them as text.
+/// `u128::MAX` has 39 digits.
+pub const AMOUNT_WIDTH: usize = 39;
+
+pub fn encode_amount(amount: u128) -> String {
+    format!("{:0width$}", amount, width = AMOUNT_WIDTH)
+}
+
+pub fn decode_amount(value: &str) -> Result<u128, String> {
+    if value.len() != AMOUNT_WIDTH || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("Invalid stored amount {:?}", value));
+    }
+    value
+        .parse::<u128>()
+        .map_err(|_| format!("Stored amount {:?} does not fit in u128", value))
+}
+
+fn row_amount(row: &SqliteRow) -> u128 {
+    decode_amount(&row.get::<String, _>("amount")).unwrap_or_else(|error| panic!("{}", error))
+}
+
+/// Describes which chain and configuration a database was built for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseMetadata {
+    pub network: String,
+    pub first_block: u64,
+    pub controller_address: String,
+    pub chain_id: u64,
+    pub schema_version: u32,
+}
+
+/// The process that may write to a database, see `acquire_writer_lease`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriterLease {
+    /// Unique per process, so a restarted process on the same host doesn't inherit the lease
+    pub holder: String,
+    pub hostname: String,
+    pub pid: u32,
+    pub acquired_at: i64,
+    pub heartbeat_at: i64,
+}
+
+impl WriterLease {
+    /// Identity of the current process, call once and keep it
+    pub fn for_this_process() -> Self {
+        let hostname = std::env::var("HOSTNAME")
+            .ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .map(|hostname| hostname.trim().to_string())
+            .filter(|hostname| !hostname.is_empty())
+            .unwrap_or_else(|| "unknown".to_string());
+        WriterLease {
+            holder: uuid::Uuid::new_v4().to_string(),
+            hostname,
+            pid: std::process::id(),
+            acquired_at: 0,
+            heartbeat_at: 0,
+        }
+    }
+}
+
+/// A balance overwritten with the node's value, kept in `brc20_prog_balance_repairs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalanceRepair {
+    pub block_height: u64,
+    pub block_hash: String,
+    pub wallet: String,
+    pub ticker: String,
+    /// The value before the repair
+    pub database_amount: u128,
+    pub node_amount: u128,
+    pub repaired_at: i64,
+}
+
+/// SQLite tuning applied to every connection
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    pub journal_mode: SqliteJournalMode,
+    pub synchronous: SqliteSynchronous,
+    /// Page cache size, negative values are in KiB (see `PRAGMA cache_size`)
+    pub cache_size: i64,
+    pub busy_timeout: Duration,
+    /// Maximum number of bytes to memory-map, 0 disables mmap
+    pub mmap_size: u64,
+    /// Number of connections in the read-only query pool
+    pub read_connections: u32,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        DatabaseOptions {
+            journal_mode: SqliteJournalMode::Wal,
+            synchronous: SqliteSynchronous::Normal,
+            cache_size: -64000,
+            busy_timeout: Duration::from_secs(5),
+            mmap_size: 256 * 1024 * 1024,
+            read_connections: 4,
+        }
+    }
+}
+
+/// A BRC20 ticker deployed in the BRC2.0 module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ticker {
+    pub ticker: String,
+    pub ticker_hash: String,
+    pub contract_address: String,
+}
+
+/// A wallet's balance in a ranked holder list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Holder {
+    pub wallet: String,
+    pub amount: u128,
+}
+
+/// Position in a ranked holder list, holders are ordered by amount descending then wallet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HolderCursor {
+    pub amount: u128,
+    pub wallet: String,
+}
+
+impl HolderCursor {
+    pub fn encode(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(format!("{}:{}", self.amount, self.wallet))
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let decoded = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+        let (amount, wallet) = decoded.split_once(':')?;
+        Some(HolderCursor {
+            amount: amount.parse().ok()?,
+            wallet: wallet.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HoldersPage {
+    pub holders: Vec<Holder>,
+    /// Sum of all balances of the ticker at the queried height
+    pub total_supply: u128,
+    /// Cursor for the next page, `None` on the last page
+    pub next_cursor: Option<HolderCursor>,
+}
+
+impl HoldersPage {
+    /// Share of the total supply held by `amount`, 0 while the ticker has no supply
+    pub fn percentage(&self, amount: u128) -> f64 {
+        if self.total_supply == 0 {
+            0.0
+        } else {
+            amount as f64 / self.total_supply as f64 * 100.0
+        }
+    }
+}
+
+/// What `reorg` would revert when rolling back to `block_height`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollbackSummary {
+    pub block_height: u64,
+    /// Indexed blocks above the target height
+    pub blocks: u64,
+    /// Historical balance rows written above the target height
+    pub balance_changes: u64,
+    /// Current balances that are restored to an older value or removed
+    pub current_balances: u64,
+    /// Tickers created above the target height
+    pub tickers: Vec<String>,
+}
+
+/// Latest balance of every wallet and ticker, from the current table or reconstructed from
+/// history at ?3. ?1 and ?2 are JSON lists of tickers and wallets to keep, or NULL for all.
+macro_rules! selection {
+    ($prefix:literal, $suffix:literal) => {
+        [
+            concat!(
+                $prefix,
+                "SELECT wallet, ticker, amount FROM brc20_prog_current_balances WHERE (?1 IS NULL OR ticker IN (SELECT value FROM json_each(?1))) AND (?2 IS NULL OR wallet IN (SELECT value FROM json_each(?2)))",
+                $suffix
+            ),
+            concat!(
+                $prefix,
+                "SELECT wallet, ticker, amount FROM (SELECT wallet, ticker, amount, ROW_NUMBER() OVER (PARTITION BY wallet, ticker ORDER BY block_height DESC, id DESC) AS row_number FROM brc20_prog_historical_balances WHERE block_height <= ?3 AND (?1 IS NULL OR ticker IN (SELECT value FROM json_each(?1))) AND (?2 IS NULL OR wallet IN (SELECT value FROM json_each(?2)))) WHERE row_number = 1",
+                $suffix
+            ),
+        ]
+    };
+}
+
+/// A consistent read-only view of the database, rows read through it all belong to
+/// `block_height` even while the tracker keeps indexing. Queries without a height read the
+/// current balances, which are only those of `block_height` in a snapshot of the indexed height.
+pub struct BalanceSnapshot {
+    tx: Transaction<'static, Sqlite>,
+    pub block_height: u64,
+    pub block_hash: Option<String>,
+    /// Whether balances are read from the current table or reconstructed from history
+    historical: bool,
+}
+
+impl BalanceSnapshot {
+    pub async fn get_balance(&mut self, wallet: String, ticker: String) -> Option<u128> {
+        read_balance(&mut self.tx, wallet, ticker).await
+    }
+
+    pub async fn get_wallet_balances(&mut self, wallet: String) -> Vec<(String, u128)> {
+        read_wallet_balances(&mut self.tx, wallet).await
+    }
+
+    /// Returns the balance a wallet had for a ticker after `block_height` was processed
+    pub async fn get_balance_at(
+        &mut self,
+        wallet: String,
+        ticker: String,
+        block_height: u64,
+    ) -> Option<u128> {
+        read_balance_at(&mut self.tx, wallet, ticker, block_height).await
+    }
+
+    /// Returns all balances of a wallet after `block_height` was processed
+    pub async fn get_wallet_balances_at(
+        &mut self,
+        wallet: String,
+        block_height: u64,
+    ) -> Vec<(String, u128)> {
+        read_wallet_balances_at(&mut self.tx, wallet, block_height).await
+    }
+
+    /// Returns every wallet with a non-zero balance of a ticker after `block_height` was processed
+    pub async fn get_ticker_holders_at(
+        &mut self,
+        ticker: String,
+        block_height: u64,
+    ) -> Vec<(String, u128)> {
+        read_ticker_holders_at(&mut self.tx, ticker, block_height).await
+    }
+
+    /// Returns holders of a ticker ranked by balance, at the current height or after
+    /// `block_height` was processed, starting after `cursor`
+    pub async fn get_top_holders(
+        &mut self,
+        ticker: String,
+        block_height: Option<u64>,
+        cursor: Option<&HolderCursor>,
+        limit: u32,
+    ) -> HoldersPage {
+        read_top_holders(&mut self.tx, ticker, block_height, cursor, limit).await
+    }
+
+    pub async fn get_ticker(&mut self, ticker: String) -> Option<Ticker> {
+        read_ticker(&mut self.tx, ticker).await
+    }
+
+    pub async fn list_tickers(&mut self) -> Vec<Ticker> {
+        read_tickers(&mut self.tx).await
+    }
+
+    /// Streams non-zero balances ordered by ticker and wallet, optionally limited to one ticker
+    pub fn balances(
+        &mut self,
+        ticker: Option<String>,
+    ) -> impl Stream<Item = Result<(String, String, u128), sqlx::Error>> + '_ {
+        let query = if self.historical {
+            sqlx::query(
+                "SELECT wallet, ticker, amount FROM (SELECT wallet, ticker, amount, ROW_NUMBER() OVER (PARTITION BY wallet, ticker ORDER BY block_height DESC, id DESC) AS row_number FROM brc20_prog_historical_balances WHERE (?1 IS NULL OR ticker = ?1) AND block_height <= ?3) WHERE row_number = 1 AND amount > ?2 ORDER BY ticker, wallet",
+            )
+            .bind(ticker)
+            .bind(encode_amount(0))
+            .bind(self.block_height as i64)
+        } else {
+            sqlx::query(
+                "SELECT wallet, ticker, amount FROM brc20_prog_current_balances WHERE (?1 IS NULL OR ticker = ?1) AND amount > ?2 ORDER BY ticker, wallet",
+            )
+            .bind(ticker)
+            .bind(encode_amount(0))
+        };
+        query
+            .fetch(&mut *self.tx)
+            .map(|row| row.map(|row| (row.get("wallet"), row.get("ticker"), row_amount(&row))))
+    }
+
+    /// Counts the balances of `tickers` and `wallets`, or of all of them if empty, including
+    /// ones that went back to zero
+    pub async fn count_balances(&mut self, tickers: &[String], wallets: &[String]) -> u64 {
+        let sql = self.selection(selection!("SELECT COUNT(*) AS count FROM (", ")"));
+        self.bind_selection(sqlx::query(sql), tickers, wallets)
+            .fetch_one(&mut *self.tx)
+            .await
+            .unwrap()
+            .get::<i64, _>("count") as u64
+    }
+
+    /// Picks up to `count` random balances out of the ones `count_balances` counts
+    pub async fn random_balances(
+        &mut self,
+        count: u32,
+        tickers: &[String],
+        wallets: &[String],
+    ) -> Vec<(String, String, u128)> {
+        let sql = self.selection(selection!(
+            "SELECT wallet, ticker, amount FROM (",
+            ") ORDER BY RANDOM() LIMIT ?4"
+        ));
+        self.bind_selection(sqlx::query(sql), tickers, wallets)
+            .bind(count as i64)
+            .fetch_all(&mut *self.tx)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.get("wallet"), r.get("ticker"), row_amount(&r)))
+            .collect()
+    }
+
+    fn selection(&self, [current, historical]: [&'static str; 2]) -> &'static str {
+        if self.historical { historical } else { current }
+    }
+
+    /// Binds the ticker and wallet lists and the height, further parameters start at ?4
+    fn bind_selection<'q>(
+        &self,
+        query: Query<'q, Sqlite, SqliteArguments<'q>>,
+        tickers: &[String],
+        wallets: &[String],
+    ) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+        let list = |values: &[String]| {
+            (!values.is_empty()).then(|| serde_json::to_string(values).unwrap())
+        };
+        query
+            .bind(list(tickers))
+            .bind(list(wallets))
+            .bind(self.block_height as i64)
+    }
+}
+
+/// The writes of one block in a single writer transaction. Readers see none of them until
+/// `commit` records the block hash, so the current balances always belong to the indexed
+/// height. Dropping it without committing rolls the block back.
+pub struct BlockWriter {
+    tx: Transaction<'static, Sqlite>,
+    block_height: u64,
+}
+
+impl BlockWriter {
+    /// Reads through the transaction, so earlier changes in the block are included
+    pub async fn get_balance(&mut self, wallet: &str, ticker: &str) -> Option<u128> {
+        let row = sqlx::query(
+            "SELECT amount FROM brc20_prog_current_balances WHERE wallet = ? AND ticker = ?",
+        )
+        .bind(wallet)
+        .bind(ticker)
+        .fetch_optional(&mut *self.tx)
+        .await
+        .unwrap();
+        row.map(|r| row_amount(&r))
+    }
+
+    pub async fn update_balance(&mut self, wallet: &str, ticker: &str, amount: u128) {
+        write_balance(&mut self.tx, self.block_height, wallet, ticker, amount).await;
+    }
+
+    pub async fn get_ticker_by_address(&mut self, contract_address: &str) -> Option<String> {
+        let row = sqlx::query("SELECT ticker FROM brc20_prog_tickers WHERE contract_address = ?")
+            .bind(contract_address)
+            .fetch_optional(&mut *self.tx)
+            .await
+            .unwrap();
+        row.map(|r| r.get::<String, _>("ticker"))
+    }
+
+    pub async fn add_ticker(&mut self, ticker: &str, ticker_hash: &str, contract_address: &str) {
+        write_ticker(
+            &mut self.tx,
+            self.block_height,
+            ticker,
+            ticker_hash,
+            contract_address,
+        )
+        .await;
+    }
+
+    /// Records the block hash and makes the block visible
+    pub async fn commit(mut self, block_hash: &str) {
+        write_block_hash(&mut self.tx, self.block_height, block_hash).await;
+        self.tx.commit().await.unwrap();
+    }
+}
+
+/// Padding would turn garbage into plausible numbers, refuse instead
+fn validate_amounts(conn: &mut SqliteConnection) -> BoxFuture<'_, Result<(), String>> {
+    Box::pin(async move {
+        let invalid = sqlx::query(
+            "SELECT 'current' AS source, id, amount FROM brc20_prog_current_balances WHERE amount = '' OR amount GLOB '*[^0-9]*' OR LENGTH(amount) > ?1 UNION ALL SELECT 'historical' AS source, id, amount FROM brc20_prog_historical_balances WHERE amount = '' OR amount GLOB '*[^0-9]*' OR LENGTH(amount) > ?1 LIMIT 10",
+        )
+        .bind(AMOUNT_WIDTH as i64)
+        .fetch_all(conn)
+        .await
+        .unwrap();
+        if invalid.is_empty() {
+            return Ok(());
+        }
+        Err(format!(
+            "Cannot migrate amounts, invalid values found: {}",
+            invalid
+                .iter()
+                .map(|r| format!(
+                    "{} balance id {} = {:?}",
+                    r.get::<String, _>("source"),
+                    r.get::<i64, _>("id"),
+                    r.get::<String, _>("amount")
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    })
+}
+
+/// ALTER TABLE can't be repeated, the column is already there if only the version was reset
+fn has_ticker_block_height(conn: &mut SqliteConnection) -> BoxFuture<'_, bool> {
+    Box::pin(async move {
+        sqlx::query("SELECT COUNT(*) AS count FROM pragma_table_info('brc20_prog_tickers') WHERE name = 'block_height'")
+            .fetch_one(conn)
+            .await
+            .unwrap()
+            .get::<i64, _>("count")
+            > 0
+    })
+}
+
+async fn write_balance(
+    conn: &mut SqliteConnection,
+    block_height: u64,
+    wallet: &str,
+    ticker: &str,
+    amount: u128,
+) {
+    let _timer = METRICS
+        .db_write_latency
+        .with_label_values(&["update_balance"])
+        .start_timer();
+    sqlx::query("INSERT INTO brc20_prog_current_balances (wallet, ticker, amount, block_height) VALUES (?, ?, ?, ?) ON CONFLICT (wallet, ticker) DO UPDATE SET amount = excluded.amount, block_height = excluded.block_height")
+        .bind(wallet)
+        .bind(ticker)
+        .bind(encode_amount(amount))
+        .bind(block_height as i64)
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO brc20_prog_historical_balances (block_height, wallet, ticker, amount) VALUES (?, ?, ?, ?)")
+        .bind(block_height as i64)
+        .bind(wallet)
+        .bind(ticker)
+        .bind(encode_amount(amount))
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+}
+
+async fn write_ticker(
+    conn: &mut SqliteConnection,
+    block_height: u64,
+    ticker: &str,
+    ticker_hash: &str,
+    contract_address: &str,
+) {
+    let _timer = METRICS
+        .db_write_latency
+        .with_label_values(&["add_ticker"])
+        .start_timer();
+    sqlx::query("INSERT INTO brc20_prog_tickers (ticker, ticker_hash, contract_address, block_height) VALUES (?, ?, ?, ?)")
+        .bind(ticker)
+        .bind(ticker_hash)
+        .bind(contract_address)
+        .bind(block_height as i64)
+        .execute(conn)
+        .await
+        .unwrap();
+}
+
+async fn write_block_hash(conn: &mut SqliteConnection, block_height: u64, block_hash: &str) {
+    let _timer = METRICS
+        .db_write_latency
+        .with_label_values(&["set_block_hash"])
+        .start_timer();
+    sqlx::query("INSERT INTO brc20_prog_block_hashes (block_height, block_hash) VALUES (?, ?)")
+        .bind(block_height as i64)
+        .bind(block_hash)
+        .execute(conn)
+        .await
+        .unwrap();
+}
+
+async fn read_balance(conn: &mut SqliteConnection, wallet: String, ticker: String) -> Option<u128> {
+    let row = sqlx::query(
+        "SELECT amount FROM brc20_prog_current_balances WHERE wallet = ? AND ticker = ?",
+    )
+    .bind(wallet)
+    .bind(ticker)
+    .fetch_optional(&mut *conn)
+    .await
+    .unwrap();
+    row.map(|r| row_amount(&r))
+}
+
+async fn read_wallet_balances(conn: &mut SqliteConnection, wallet: String) -> Vec<(String, u128)> {
+    let rows = sqlx::query(
+        "SELECT ticker, amount FROM brc20_prog_current_balances WHERE wallet = ? ORDER BY ticker",
+    )
+    .bind(wallet)
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap();
+    rows.into_iter()
+        .map(|r| (r.get("ticker"), row_amount(&r)))
+        .collect()
+}
+
+async fn read_balance_at(
+    conn: &mut SqliteConnection,
+    wallet: String,
+    ticker: String,
+    block_height: u64,
+) -> Option<u128> {
+    let row = sqlx::query(
+        "SELECT amount FROM brc20_prog_historical_balances WHERE wallet = ? AND ticker = ? AND block_height <= ? ORDER BY block_height DESC, id DESC LIMIT 1",
+    )
+    .bind(wallet)
+    .bind(ticker)
+    .bind(block_height as i64)
+    .fetch_optional(&mut *conn)
+    .await
+    .unwrap();
+    row.map(|r| row_amount(&r))
+}
+
+async fn read_wallet_balances_at(
+    conn: &mut SqliteConnection,
+    wallet: String,
+    block_height: u64,
+) -> Vec<(String, u128)> {
+    let rows = sqlx::query(
+        "SELECT ticker, amount FROM (SELECT ticker, amount, ROW_NUMBER() OVER (PARTITION BY ticker ORDER BY block_height DESC, id DESC) AS row_number FROM brc20_prog_historical_balances WHERE wallet = ? AND block_height <= ?) WHERE row_number = 1 ORDER BY ticker",
+    )
+    .bind(wallet)
+    .bind(block_height as i64)
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap();
+    rows.into_iter()
+        .map(|r| (r.get("ticker"), row_amount(&r)))
+        .collect()
+}
+
+async fn read_ticker_holders_at(
+    conn: &mut SqliteConnection,
+    ticker: String,
+    block_height: u64,
+) -> Vec<(String, u128)> {
+    let rows = sqlx::query(
+        "SELECT wallet, amount FROM (SELECT wallet, amount, ROW_NUMBER() OVER (PARTITION BY wallet ORDER BY block_height DESC, id DESC) AS row_number FROM brc20_prog_historical_balances WHERE ticker = ? AND block_height <= ?) WHERE row_number = 1 ORDER BY wallet",
+    )
+    .bind(ticker)
+    .bind(block_height as i64)
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap();
+    rows.into_iter()
+        .map(|r| (r.get("wallet"), row_amount(&r)))
+        .filter(|(_, amount)| *amount > 0)
+        .collect()
+}
+
+async fn read_top_holders(
+    conn: &mut SqliteConnection,
+    ticker: String,
+    block_height: Option<u64>,
+    cursor: Option<&HolderCursor>,
+    limit: u32,
+) -> HoldersPage {
+    let balances = match block_height {
+        None => "SELECT wallet, amount FROM brc20_prog_current_balances WHERE ticker = ?1",
+        Some(_) => {
+            "SELECT wallet, amount FROM (SELECT wallet, amount, ROW_NUMBER() OVER (PARTITION BY wallet ORDER BY block_height DESC, id DESC) AS row_number FROM brc20_prog_historical_balances WHERE ticker = ?1 AND block_height <= ?2) WHERE row_number = 1"
+        }
+    };
+
+    // SQLite sums text as floating point, so the digits are summed in slices that fit an
+    // integer and put together here
+    let parts = sqlx::query(&format!(
+        "SELECT SUM(CAST(SUBSTR(amount, 1, 9) AS INTEGER)) AS part0, SUM(CAST(SUBSTR(amount, 10, 10) AS INTEGER)) AS part1, SUM(CAST(SUBSTR(amount, 20, 10) AS INTEGER)) AS part2, SUM(CAST(SUBSTR(amount, 30, 10) AS INTEGER)) AS part3 FROM ({})",
+        balances
+    ))
+    .bind(ticker.clone())
+    .bind(block_height.map(|h| h as i64))
+    .fetch_one(&mut *conn)
+    .await
+    .unwrap();
+    let total_supply = ["part0", "part1", "part2", "part3"]
+        .iter()
+        .map(|part| parts.get::<Option<i64>, _>(*part).unwrap_or(0) as u128)
+        .try_fold(0u128, |total, part| {
+            total.checked_mul(10_000_000_000)?.checked_add(part)
+        })
+        .expect("Overflow");
+
+    let rows = sqlx::query(&format!(
+        "SELECT wallet, amount FROM ({}) WHERE amount > ?3 AND (?4 IS NULL OR amount < ?4 OR (amount = ?4 AND wallet > ?5)) ORDER BY amount DESC, wallet ASC LIMIT ?6",
+        balances
+    ))
+    .bind(ticker)
+    .bind(block_height.map(|h| h as i64))
+    .bind(encode_amount(0))
+    .bind(cursor.map(|c| encode_amount(c.amount)))
+    .bind(cursor.map(|c| c.wallet.clone()))
+    .bind(limit as i64 + 1)
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap();
+
+    let mut holders: Vec<Holder> = rows
+        .into_iter()
+        .map(|r| Holder {
+            wallet: r.get("wallet"),
+            amount: row_amount(&r),
+        })
+        .collect();
+
+    // One extra row was fetched to know whether there is a next page
+    let next_cursor = if holders.len() > limit as usize {
+        holders.truncate(limit as usize);
+        holders.last().map(|h| HolderCursor {
+            amount: h.amount,
+            wallet: h.wallet.clone(),
+        })
+    } else {
+        None
+    };
+
+    HoldersPage {
+        holders,
+        total_supply,
+        next_cursor,
+    }
+}
+
+async fn read_ticker(conn: &mut SqliteConnection, ticker: String) -> Option<Ticker> {
+    let row = sqlx::query(
+        "SELECT ticker, ticker_hash, contract_address FROM brc20_prog_tickers WHERE ticker = ?",
+    )
+    .bind(ticker)
+    .fetch_optional(&mut *conn)
+    .await
+    .unwrap();
+    row.map(|r| Ticker {
+        ticker: r.get("ticker"),
+        ticker_hash: r.get("ticker_hash"),
+        contract_address: r.get("contract_address"),
+    })
+}
+
+async fn read_tickers(conn: &mut SqliteConnection) -> Vec<Ticker> {
+    let rows = sqlx::query(
+        "SELECT ticker, ticker_hash, contract_address FROM brc20_prog_tickers ORDER BY ticker",
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap();
+    rows.into_iter()
+        .map(|r| Ticker {
+            ticker: r.get("ticker"),
+            ticker_hash: r.get("ticker_hash"),
+            contract_address: r.get("contract_address"),
+        })
+        .collect()
+}
+
+/// Writes go through a single writer connection, queries through a separate read-only pool
+/// so long-running readers don't hold up indexing.
+#[derive(Clone)]
 pub struct BalanceDatabase {
-    db: SqlitePool,
+    writer: SqlitePool,
+    reader: SqlitePool,
     first_block: i64,
 }
 
 impl BalanceDatabase {
-    pub async fn new(db_url: &str, first_block: i64) -> Self {
+    /// Read-only commands check this first, `new` would create an empty database
+    pub async fn exists(db_url: &str) -> bool {
+        Sqlite::database_exists(db_url).await.unwrap_or(false)
+    }
+
+    pub async fn new(db_url: &str, first_block: i64, options: &DatabaseOptions) -> Self {
         if !Sqlite::database_exists(db_url).await.unwrap_or(false) {
             match Sqlite::create_database(db_url).await {
                 Ok(_) => {}
                 Err(error) => panic!("error: {}", error),
             }
         }
+
+        let connect_options = SqliteConnectOptions::from_str(db_url)
+            .unwrap()
+            .synchronous(options.synchronous)
+            .busy_timeout(options.busy_timeout)
+            .pragma("cache_size", options.cache_size.to_string())
+            .pragma("mmap_size", options.mmap_size.to_string());
+
+        // The writer owns the journal mode, readers only need to see it
+        let writer = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(connect_options.clone().journal_mode(options.journal_mode))
+            .await
+            .unwrap();
+        let reader = SqlitePoolOptions::new()
+            .max_connections(options.read_connections.max(1))
+            .connect_with(connect_options.pragma("query_only", "ON"))
+            .await
+            .unwrap();
+
         BalanceDatabase {
-            db: SqlitePool::connect(db_url).await.unwrap(),
+            writer,
+            reader,
             first_block,
         }
     }
 
+    /// Creates the tables and migrates them to `SCHEMA_VERSION`, only call while holding the
+    /// writer lease
     pub async fn init(&self) {
         let init_query = String::from_utf8(
             Sql::get("init.sql")
@@ -32,7 +788,101 @@ impl BalanceDatabase {
                 .to_vec(),
         )
         .expect("Failed to read init.sql");
-        sqlx::query(&init_query).execute(&self.db).await.unwrap();
+        sqlx::query(&init_query)
+            .execute(&self.writer)
+            .await
+            .unwrap();
+        self.migrate().await;
+    }
+
+    async fn table_exists(&self, table: &str) -> bool {
+        sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(table)
+            .fetch_optional(&self.reader)
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    pub async fn get_schema_version(&self) -> Option<u32> {
+        if !self.table_exists("brc20_prog_metadata").await {
+            return None;
+        }
+        let row = sqlx::query("SELECT value FROM brc20_prog_metadata WHERE key = 'schema_version'")
+            .fetch_optional(&self.reader)
+            .await
+            .unwrap();
+        row.map(|r| {
+            r.get::<String, _>("value")
+                .parse()
+                .expect("Failed to parse schema_version")
+        })
+    }
+
+    /// Fails unless the tables exist at `SCHEMA_VERSION`. Commands that only read call this
+    /// instead of `init`, which would create or migrate them without holding the lease.
+    pub async fn check_schema(&self) -> Result<(), String> {
+        if !self.table_exists("brc20_prog_current_balances").await {
+            return Err("Database is not initialized, run the tracker first".to_string());
+        }
+        match self.get_schema_version().await.unwrap_or(1) {
+            SCHEMA_VERSION => Ok(()),
+            version if version > SCHEMA_VERSION => Err(format!(
+                "Database schema version {} is newer than the supported version {}",
+                version, SCHEMA_VERSION
+            )),
+            version => Err(format!(
+                "Database schema version {} is older than version {}, run the tracker to migrate it",
+                version, SCHEMA_VERSION
+            )),
+        }
+    }
+
+    /// Brings an existing database up to `SCHEMA_VERSION`, each migration runs in its own
+    /// transaction together with the version bump
+    async fn migrate(&self) {
+        let version = self.get_schema_version().await.unwrap_or(1);
+        if version > SCHEMA_VERSION {
+            panic!(
+                "Database schema version {} is newer than the supported version {}",
+                version, SCHEMA_VERSION
+            );
+        }
+
+        for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+            info!("Migrating database to schema version {}", migration.version);
+            let file = migration.file;
+            let migration_query = String::from_utf8(
+                Sql::get(file)
+                    .unwrap_or_else(|| panic!("Failed to read {}", file))
+                    .data
+                    .to_vec(),
+            )
+            .unwrap_or_else(|_| panic!("Failed to read {}", file));
+
+            let mut tx = self.writer.begin().await.unwrap();
+            if let Some(validate) = migration.validate {
+                validate(&mut tx)
+                    .await
+                    .unwrap_or_else(|error| panic!("{}", error));
+            }
+            let applied = match migration.applied {
+                Some(applied) => applied(&mut tx).await,
+                None => false,
+            };
+            if !applied {
+                sqlx::query(&migration_query)
+                    .execute(&mut *tx)
+                    .await
+                    .unwrap();
+            }
+            sqlx::query("INSERT INTO brc20_prog_metadata (key, value) VALUES ('schema_version', ?) ON CONFLICT (key) DO UPDATE SET value = excluded.value")
+                .bind(migration.version.to_string())
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+            tx.commit().await.unwrap();
+        }
     }
 
     pub async fn reset(&self) {
@@ -42,21 +892,315 @@ impl BalanceDatabase {
                 .data
                 .to_vec(),
         )
-        .expect("Failed to read reset.sql");
-        println!("Executing reset query:\n{}", reset_query);
-        sqlx::query(&reset_query).execute(&self.db).await.unwrap();
+        .expect("Failed to read reset.sql");
+        debug!("Executing reset query:\n{}", reset_query);
+        sqlx::query(&reset_query)
+            .execute(&self.writer)
+            .await
+            .unwrap();
+    }
+
+    /// Checks that the database still answers queries
+    pub async fn is_reachable(&self) -> bool {
+        sqlx::query("SELECT 1").execute(&self.reader).await.is_ok()
+    }
+
+    /// Moves the WAL into the main database file and closes both pools, waiting for running
+    /// queries to finish
+    pub async fn close(&self) {
+        self.reader.close().await;
+        if let Err(err) = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&self.writer)
+            .await
+        {
+            warn!(error = %err, "Failed to checkpoint the database");
+        }
+        self.writer.close().await;
+    }
+
+    pub fn first_block(&self) -> u64 {
+        self.first_block as u64
+    }
+
+    pub async fn get_metadata(&self) -> Option<DatabaseMetadata> {
+        let rows = sqlx::query("SELECT key, value FROM brc20_prog_metadata")
+            .fetch_all(&self.reader)
+            .await
+            .unwrap();
+        if !rows.iter().any(|r| r.get::<String, _>("key") == "network") {
+            return None;
+        }
+        let value = |key: &str| -> String {
+            rows.iter()
+                .find(|r| r.get::<String, _>("key") == key)
+                .map(|r| r.get::<String, _>("value"))
+                .unwrap_or_else(|| panic!("Missing metadata key {}", key))
+        };
+        Some(DatabaseMetadata {
+            network: value("network"),
+            first_block: value("first_block")
+                .parse()
+                .expect("Failed to parse first_block"),
+            controller_address: value("controller_address"),
+            chain_id: value("chain_id").parse().expect("Failed to parse chain_id"),
+            schema_version: value("schema_version")
+                .parse()
+                .expect("Failed to parse schema_version"),
+        })
+    }
+
+    pub async fn set_metadata(&self, metadata: &DatabaseMetadata) {
+        let mut tx = self.writer.begin().await.unwrap();
+        for (key, value) in [
+            ("network", metadata.network.clone()),
+            ("first_block", metadata.first_block.to_string()),
+            ("controller_address", metadata.controller_address.clone()),
+            ("chain_id", metadata.chain_id.to_string()),
+            ("schema_version", metadata.schema_version.to_string()),
+        ] {
+            sqlx::query("INSERT INTO brc20_prog_metadata (key, value) VALUES (?, ?) ON CONFLICT (key) DO UPDATE SET value = excluded.value")
+                .bind(key)
+                .bind(value)
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+        }
+        tx.commit().await.unwrap();
+    }
+
+    /// Takes the writer lease for `lease.holder` unless another process holds it and has sent
+    /// a heartbeat within `timeout`. Returns the current holder if the lease is taken.
+    pub async fn acquire_writer_lease(
+        &self,
+        lease: &WriterLease,
+        timeout: Duration,
+    ) -> Result<(), WriterLease> {
+        // The first write to a new database, everything else waits for the lease
+        let lease_query = String::from_utf8(
+            Sql::get("writer_lease.sql")
+                .expect("Failed to read writer_lease.sql")
+                .data
+                .to_vec(),
+        )
+        .expect("Failed to read writer_lease.sql");
+        sqlx::query(&lease_query)
+            .execute(&self.writer)
+            .await
+            .unwrap();
+
+        let now = unix_time();
+        // A single statement, so two processes starting at once can't both win
+        let result = sqlx::query(
+            "INSERT INTO brc20_prog_writer_lease (id, holder, hostname, pid, acquired_at, heartbeat_at) VALUES (1, ?1, ?2, ?3, ?4, ?4) ON CONFLICT (id) DO UPDATE SET holder = excluded.holder, hostname = excluded.hostname, pid = excluded.pid, acquired_at = excluded.acquired_at, heartbeat_at = excluded.heartbeat_at WHERE brc20_prog_writer_lease.holder = excluded.holder OR brc20_prog_writer_lease.heartbeat_at < ?5",
+        )
+        .bind(&lease.holder)
+        .bind(&lease.hostname)
+        .bind(lease.pid as i64)
+        .bind(now)
+        .bind(now - timeout.as_secs() as i64)
+        .execute(&self.writer)
+        .await
+        .unwrap();
+        if result.rows_affected() == 1 {
+            return Ok(());
+        }
+        Err(self
+            .get_writer_lease()
+            .await
+            .expect("Writer lease disappeared while acquiring it"))
+    }
+
+    /// Returns false if the lease was taken over, the caller must stop writing
+    pub async fn heartbeat_writer_lease(&self, lease: &WriterLease) -> bool {
+        sqlx::query(
+            "UPDATE brc20_prog_writer_lease SET heartbeat_at = ? WHERE id = 1 AND holder = ?",
+        )
+        .bind(unix_time())
+        .bind(&lease.holder)
+        .execute(&self.writer)
+        .await
+        .unwrap()
+        .rows_affected()
+            == 1
+    }
+
+    pub async fn release_writer_lease(&self, lease: &WriterLease) {
+        sqlx::query("DELETE FROM brc20_prog_writer_lease WHERE id = 1 AND holder = ?")
+            .bind(&lease.holder)
+            .execute(&self.writer)
+            .await
+            .unwrap();
+    }
+
+    pub async fn get_writer_lease(&self) -> Option<WriterLease> {
+        if !self.table_exists("brc20_prog_writer_lease").await {
+            return None;
+        }
+        sqlx::query("SELECT holder, hostname, pid, acquired_at, heartbeat_at FROM brc20_prog_writer_lease WHERE id = 1")
+            .fetch_optional(&self.writer)
+            .await
+            .unwrap()
+            .map(|row| WriterLease {
+                holder: row.get("holder"),
+                hostname: row.get("hostname"),
+                pid: row.get::<i64, _>("pid") as u32,
+                acquired_at: row.get("acquired_at"),
+                heartbeat_at: row.get("heartbeat_at"),
+            })
+    }
+
+    pub async fn get_balance(&self, wallet: String, ticker: String) -> Option<u128> {
+        read_balance(&mut self.reader.acquire().await.unwrap(), wallet, ticker).await
+    }
+
+    pub async fn get_wallet_balances(&self, wallet: String) -> Vec<(String, u128)> {
+        read_wallet_balances(&mut self.reader.acquire().await.unwrap(), wallet).await
+    }
+
+    /// Returns the balance a wallet had for a ticker after `block_height` was processed
+    pub async fn get_balance_at(
+        &self,
+        wallet: String,
+        ticker: String,
+        block_height: u64,
+    ) -> Option<u128> {
+        read_balance_at(
+            &mut self.reader.acquire().await.unwrap(),
+            wallet,
+            ticker,
+            block_height,
+        )
+        .await
+    }
+
+    /// Returns all balances of a wallet after `block_height` was processed
+    pub async fn get_wallet_balances_at(
+        &self,
+        wallet: String,
+        block_height: u64,
+    ) -> Vec<(String, u128)> {
+        read_wallet_balances_at(
+            &mut self.reader.acquire().await.unwrap(),
+            wallet,
+            block_height,
+        )
+        .await
+    }
+
+    /// Returns every wallet with a non-zero balance of a ticker after `block_height` was processed
+    pub async fn get_ticker_holders_at(
+        &self,
+        ticker: String,
+        block_height: u64,
+    ) -> Vec<(String, u128)> {
+        read_ticker_holders_at(
+            &mut self.reader.acquire().await.unwrap(),
+            ticker,
+            block_height,
+        )
+        .await
+    }
+
+    /// Returns holders of a ticker ranked by balance, at the current height or after
+    /// `block_height` was processed, starting after `cursor`
+    pub async fn get_top_holders(
+        &self,
+        ticker: String,
+        block_height: Option<u64>,
+        cursor: Option<&HolderCursor>,
+        limit: u32,
+    ) -> HoldersPage {
+        // The total supply and the page are read in one transaction
+        let mut tx = self.reader.begin().await.unwrap();
+        read_top_holders(&mut tx, ticker, block_height, cursor, limit).await
+    }
+
+    /// Opens a read transaction at the indexed height, or at `block_height` if it's given.
+    /// Fails if `block_height` hasn't been indexed yet.
+    pub async fn snapshot(&self, block_height: Option<u64>) -> Result<BalanceSnapshot, String> {
+        let mut tx = self.reader.begin().await.unwrap();
+        let indexed_height =
+            sqlx::query("SELECT MAX(block_height) AS max_height FROM brc20_prog_block_hashes")
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap()
+                .get::<Option<i64>, _>("max_height")
+                .unwrap_or(self.first_block - 1) as u64;
+
+        let block_height = match block_height {
+            Some(height) if height > indexed_height => {
+                return Err(format!(
+                    "Height {} is not indexed yet, indexed height is {}",
+                    height, indexed_height
+                ));
+            }
+            Some(height) => height,
+            None => indexed_height,
+        };
+        let block_hash =
+            sqlx::query("SELECT block_hash FROM brc20_prog_block_hashes WHERE block_height = ?")
+                .bind(block_height as i64)
+                .fetch_optional(&mut *tx)
+                .await
+                .unwrap()
+                .map(|r| r.get::<String, _>("block_hash"));
+
+        Ok(BalanceSnapshot {
+            tx,
+            block_hash,
+            historical: block_height != indexed_height,
+            block_height,
+        })
+    }
+
+    /// Up to `limit` of the balances `BalanceSnapshot::count_balances` counts at
+    /// `block_height`, ordered by wallet and ticker and starting after the `after` key.
+    ///
+    /// Each page is a single statement, so paging through all balances doesn't keep a read
+    /// transaction open. Balances changed since `block_height` are reconstructed from history,
+    /// so every page reads the same height while the tracker keeps indexing.
+    pub async fn balances_page(
+        &self,
+        block_height: u64,
+        tickers: &[String],
+        wallets: &[String],
+        after: Option<(String, String)>,
+        limit: u32,
+    ) -> Vec<(String, String, u128)> {
+        let list = |values: &[String]| {
+            (!values.is_empty()).then(|| serde_json::to_string(values).unwrap())
+        };
+        // Every key sorts after empty strings, the first page starts there
+        let (after_wallet, after_ticker) = after.unwrap_or_default();
+        sqlx::query(
+            "SELECT wallet, ticker, amount FROM (SELECT wallet, ticker, CASE WHEN block_height <= ?3 THEN amount ELSE (SELECT amount FROM brc20_prog_historical_balances AS history WHERE history.wallet = current.wallet AND history.ticker = current.ticker AND history.block_height <= ?3 ORDER BY history.block_height DESC, history.id DESC LIMIT 1) END AS amount FROM brc20_prog_current_balances AS current WHERE (?1 IS NULL OR ticker IN (SELECT value FROM json_each(?1))) AND (?2 IS NULL OR wallet IN (SELECT value FROM json_each(?2))) AND (wallet, ticker) > (?5, ?6)) WHERE amount IS NOT NULL ORDER BY wallet, ticker LIMIT ?4",
+        )
+        .bind(list(tickers))
+        .bind(list(wallets))
+        .bind(block_height as i64)
+        .bind(limit as i64)
+        .bind(after_wallet)
+        .bind(after_ticker)
+        .fetch_all(&self.reader)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| (r.get("wallet"), r.get("ticker"), row_amount(&r)))
+        .collect()
     }
 
-    pub async fn get_balance(&self, wallet: String, ticker: String) -> Option<u128> {
-        let row = sqlx::query(
-            "SELECT amount FROM brc20_prog_current_balances WHERE wallet = ? AND ticker = ?",
+    /// A wallet and ticker whose balance at the indexed height differs from its balance at
+    /// `block_height`, if any balance changed since
+    pub async fn changed_balance(&self, block_height: u64) -> Option<(String, String)> {
+        sqlx::query(
+            "SELECT wallet, ticker FROM brc20_prog_current_balances AS current WHERE block_height > ?1 AND amount != COALESCE((SELECT amount FROM brc20_prog_historical_balances AS history WHERE history.wallet = current.wallet AND history.ticker = current.ticker AND history.block_height <= ?1 ORDER BY history.block_height DESC, history.id DESC LIMIT 1), ?2) LIMIT 1",
         )
-        .bind(wallet)
-        .bind(ticker)
-        .fetch_optional(&self.db)
+        .bind(block_height as i64)
+        .bind(encode_amount(0))
+        .fetch_optional(&self.reader)
         .await
-        .unwrap();
-        row.map(|r| r.get::<String, _>("amount").parse::<u128>().unwrap_or(0))
+        .unwrap()
+        .map(|r| (r.get("wallet"), r.get("ticker")))
     }
 
     pub async fn update_balance(
@@ -66,49 +1210,129 @@ impl BalanceDatabase {
         ticker: String,
         amount: u128,
     ) {
-        let mut tx = self.db.begin().await.unwrap();
+        let mut tx = self.writer.begin().await.unwrap();
+        write_balance(&mut tx, block_height, &wallet, &ticker, amount).await;
+        tx.commit().await.unwrap();
+    }
+
+    /// Sets a balance to the node's value at `repair.block_height`, which has to be the indexed
+    /// height. The historical row keeps reconstructions at later heights right, and the repair
+    /// is recorded with the old value for later analysis.
+    ///
+    /// Returns `false` without writing anything if the indexed height moved on or the balance
+    /// no longer is `repair.database_amount`, it changed since it was compared to the node.
+    pub async fn repair_balance(&self, repair: &BalanceRepair) -> bool {
+        let mut tx = self.writer.begin().await.unwrap();
+        let indexed_height =
+            sqlx::query("SELECT MAX(block_height) AS max_height FROM brc20_prog_block_hashes")
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap()
+                .get::<Option<i64>, _>("max_height");
+        let amount = sqlx::query(
+            "SELECT amount FROM brc20_prog_current_balances WHERE wallet = ? AND ticker = ?",
+        )
+        .bind(&repair.wallet)
+        .bind(&repair.ticker)
+        .fetch_optional(&mut *tx)
+        .await
+        .unwrap()
+        .map(|r| row_amount(&r))
+        .unwrap_or(0);
+        if indexed_height != Some(repair.block_height as i64) || amount != repair.database_amount {
+            return false;
+        }
         sqlx::query("INSERT INTO brc20_prog_current_balances (wallet, ticker, amount, block_height) VALUES (?, ?, ?, ?) ON CONFLICT (wallet, ticker) DO UPDATE SET amount = excluded.amount, block_height = excluded.block_height")
-            .bind(wallet.clone())
-            .bind(ticker.clone())
-            .bind(amount.to_string())
-            .bind(block_height as i64)
+            .bind(&repair.wallet)
+            .bind(&repair.ticker)
+            .bind(encode_amount(repair.node_amount))
+            .bind(repair.block_height as i64)
             .execute(&mut *tx)
             .await
             .unwrap();
         sqlx::query("INSERT INTO brc20_prog_historical_balances (block_height, wallet, ticker, amount) VALUES (?, ?, ?, ?)")
-            .bind(block_height as i64)
-            .bind(wallet)
-            .bind(ticker)
-            .bind(amount.to_string())
+            .bind(repair.block_height as i64)
+            .bind(&repair.wallet)
+            .bind(&repair.ticker)
+            .bind(encode_amount(repair.node_amount))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO brc20_prog_balance_repairs (block_height, block_hash, wallet, ticker, database_amount, node_amount, repaired_at) VALUES (?, ?, ?, ?, ?, ?, ?)")
+            .bind(repair.block_height as i64)
+            .bind(&repair.block_hash)
+            .bind(&repair.wallet)
+            .bind(&repair.ticker)
+            .bind(encode_amount(repair.database_amount))
+            .bind(encode_amount(repair.node_amount))
+            .bind(repair.repaired_at)
             .execute(&mut *tx)
             .await
             .unwrap();
         tx.commit().await.unwrap();
+        true
     }
 
-    pub async fn add_ticker(&self, ticker: String, ticker_hash: String, contract_address: String) {
-        sqlx::query("INSERT INTO brc20_prog_tickers (ticker, ticker_hash, contract_address) VALUES (?, ?, ?)")
-            .bind(ticker)
-            .bind(ticker_hash)
-            .bind(contract_address)
-            .execute(&self.db)
+    /// Every repair made so far, oldest first
+    pub async fn get_balance_repairs(&self) -> Vec<BalanceRepair> {
+        sqlx::query("SELECT block_height, block_hash, wallet, ticker, database_amount, node_amount, repaired_at FROM brc20_prog_balance_repairs ORDER BY id")
+            .fetch_all(&self.reader)
             .await
-            .unwrap();
+            .unwrap()
+            .into_iter()
+            .map(|row| BalanceRepair {
+                block_height: row.get::<i64, _>("block_height") as u64,
+                block_hash: row.get("block_hash"),
+                wallet: row.get("wallet"),
+                ticker: row.get("ticker"),
+                database_amount: decode_amount(&row.get::<String, _>("database_amount"))
+                    .unwrap_or_else(|error| panic!("{}", error)),
+                node_amount: decode_amount(&row.get::<String, _>("node_amount"))
+                    .unwrap_or_else(|error| panic!("{}", error)),
+                repaired_at: row.get("repaired_at"),
+            })
+            .collect()
+    }
+
+    pub async fn add_ticker(
+        &self,
+        block_height: u64,
+        ticker: String,
+        ticker_hash: String,
+        contract_address: String,
+    ) {
+        let mut conn = self.writer.acquire().await.unwrap();
+        write_ticker(
+            &mut conn,
+            block_height,
+            &ticker,
+            &ticker_hash,
+            &contract_address,
+        )
+        .await;
     }
 
     pub async fn get_ticker_by_address(&self, contract_address: String) -> Option<String> {
         let row = sqlx::query("SELECT ticker FROM brc20_prog_tickers WHERE contract_address = ?")
             .bind(contract_address)
-            .fetch_optional(&self.db)
+            .fetch_optional(&self.reader)
             .await
             .unwrap();
         row.map(|r| r.get::<String, _>("ticker"))
     }
 
+    pub async fn get_ticker(&self, ticker: String) -> Option<Ticker> {
+        read_ticker(&mut self.reader.acquire().await.unwrap(), ticker).await
+    }
+
+    pub async fn list_tickers(&self) -> Vec<Ticker> {
+        read_tickers(&mut self.reader.acquire().await.unwrap()).await
+    }
+
     pub async fn get_last_block(&self) -> u64 {
         let row =
             sqlx::query("SELECT MAX(block_height) as max_height FROM brc20_prog_block_hashes")
-                .fetch_one(&self.db)
+                .fetch_one(&self.reader)
                 .await
                 .unwrap();
         (row.get::<Option<i64>, _>("max_height")
@@ -123,19 +1347,23 @@ impl BalanceDatabase {
         let row =
             sqlx::query("SELECT block_hash FROM brc20_prog_block_hashes WHERE block_height = ?")
                 .bind(block_height as i64)
-                .fetch_optional(&self.db)
+                .fetch_optional(&self.reader)
                 .await
                 .unwrap();
         row.map(|r| r.get::<String, _>("block_hash"))
     }
 
     pub async fn set_block_hash(&self, block_height: u64, block_hash: String) {
-        sqlx::query("INSERT INTO brc20_prog_block_hashes (block_height, block_hash) VALUES (?, ?)")
-            .bind(block_height as i64)
-            .bind(block_hash)
-            .execute(&self.db)
-            .await
-            .unwrap();
+        let mut conn = self.writer.acquire().await.unwrap();
+        write_block_hash(&mut conn, block_height, &block_hash).await;
+    }
+
+    /// Starts writing `block_height`, see `BlockWriter`
+    pub async fn begin_block(&self, block_height: u64) -> BlockWriter {
+        BlockWriter {
+            tx: self.writer.begin().await.unwrap(),
+            block_height,
+        }
     }
 
     pub async fn validate_block_hash(&self, block_height: u64, block_hash: String) -> bool {
@@ -143,54 +1371,78 @@ impl BalanceDatabase {
             return true;
         }
         let stored_hash = self.get_block_hash(block_height).await;
-        stored_hash.map_or(false, |h| h == block_hash)
+        stored_hash.is_some_and(|h| h == block_hash)
     }
 
     pub async fn clear_residue(&self) {
         // Reorg deletes all data after the last processed block
-        // So it works as a cleanup mechanism
-        self.reorg(self.get_last_block().await).await;
+        // So it works as a cleanup mechanism. Nothing processed yet wraps around to block 0.
+        self.reorg(self.get_last_block().await.wrapping_add(1))
+            .await;
     }
 
-    pub async fn random_wallet_ticker_pairs(&self, count: i32) -> Vec<(String, String, u128)> {
-        let rows = sqlx::query(
-            "SELECT wallet, ticker, amount FROM brc20_prog_current_balances WHERE id IN (SELECT id FROM brc20_prog_current_balances ORDER BY RANDOM() LIMIT ?)",
+    pub async fn rollback_summary(&self, block_height: u64) -> RollbackSummary {
+        let count = |query: &'static str| async move {
+            sqlx::query(query)
+                .bind(block_height as i64)
+                .fetch_one(&self.reader)
+                .await
+                .unwrap()
+                .get::<i64, _>("count") as u64
+        };
+        let tickers = sqlx::query(
+            "SELECT ticker FROM brc20_prog_tickers WHERE block_height > ? ORDER BY block_height, ticker",
         )
-        .bind(count)
-        .fetch_all(&self.db)
+        .bind(block_height as i64)
+        .fetch_all(&self.reader)
         .await
-        .unwrap();
-        rows.into_iter()
-            .map(|r| {
-                (
-                    r.get("wallet"),
-                    r.get("ticker"),
-                    r.get::<String, _>("amount")
-                        .parse::<u128>()
-                        .expect("Failed to parse amount"),
-                )
-            })
-            .collect()
+        .unwrap()
+        .into_iter()
+        .map(|row| row.get("ticker"))
+        .collect();
+
+        RollbackSummary {
+            block_height,
+            blocks: count(
+                "SELECT COUNT(*) AS count FROM brc20_prog_block_hashes WHERE block_height > ?",
+            )
+            .await,
+            balance_changes: count(
+                "SELECT COUNT(*) AS count FROM brc20_prog_historical_balances WHERE block_height > ?",
+            )
+            .await,
+            current_balances: count(
+                "SELECT COUNT(*) AS count FROM brc20_prog_current_balances WHERE block_height > ?",
+            )
+            .await,
+            tickers,
+        }
     }
 
+    /// Reverts the balances, block hashes and tickers of `from_block_height` and every block
+    /// after it
     pub async fn reorg(&self, from_block_height: u64) {
-        let mut tx = self.db.begin().await.unwrap();
+        let _timer = METRICS
+            .db_write_latency
+            .with_label_values(&["reorg"])
+            .start_timer();
+        let mut tx = self.writer.begin().await.unwrap();
         let from_block_height = from_block_height as i64;
 
-        sqlx::query("DELETE FROM brc20_prog_block_hashes WHERE block_height > ?")
+        sqlx::query("DELETE FROM brc20_prog_block_hashes WHERE block_height >= ?")
             .bind(from_block_height)
             .execute(&mut *tx)
             .await
             .unwrap();
 
-        sqlx::query("DELETE FROM brc20_prog_historical_balances WHERE block_height > ?")
+        sqlx::query("DELETE FROM brc20_prog_historical_balances WHERE block_height >= ?")
             .bind(from_block_height)
             .execute(&mut *tx)
             .await
             .unwrap();
 
         let deleted_rows = sqlx::query(
-            "DELETE from brc20_prog_current_balances WHERE block_height > ? RETURNING wallet, ticker",
+            "DELETE from brc20_prog_current_balances WHERE block_height >= ? RETURNING wallet, ticker",
         )
         .bind(from_block_height)
         .fetch_all(&mut *tx)
@@ -201,7 +1453,7 @@ impl BalanceDatabase {
             let wallet: String = row.get("wallet");
             let ticker: String = row.get("ticker");
             // Restore the balance for the deleted row
-            if let Some(balance_row) = sqlx::query("SELECT block_height, amount FROM brc20_prog_historical_balances WHERE wallet = ? AND ticker = ? ORDER BY block_height DESC LIMIT 1")
+            if let Some(balance_row) = sqlx::query("SELECT block_height, amount FROM brc20_prog_historical_balances WHERE wallet = ? AND ticker = ? ORDER BY block_height DESC, id DESC LIMIT 1")
                 .bind(wallet.clone())
                 .bind(ticker.clone())
                 .fetch_optional(&mut *tx)
@@ -221,7 +1473,13 @@ impl BalanceDatabase {
                 }
         }
 
-        sqlx::query("DELETE FROM brc20_prog_block_hashes WHERE block_height > ?")
+        sqlx::query("DELETE FROM brc20_prog_block_hashes WHERE block_height >= ?")
+            .bind(from_block_height)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        sqlx::query("DELETE FROM brc20_prog_tickers WHERE block_height >= ?")
             .bind(from_block_height)
             .execute(&mut *tx)
             .await
@@ -232,14 +1490,62 @@ impl BalanceDatabase {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use std::ops::Deref;
+
+    use futures::TryStreamExt;
+
     use super::*;
 
+    /// An initialized database under `tmp`, removed again when dropped
+    pub(crate) struct TestDatabase {
+        db: BalanceDatabase,
+        path: String,
+    }
+
+    impl TestDatabase {
+        pub(crate) async fn new() -> Self {
+            let db = Self::uninitialized().await;
+            db.init().await;
+            db
+        }
+
+        /// Without any tables, for what has to happen before `init`
+        async fn uninitialized() -> Self {
+            std::fs::create_dir_all("tmp").unwrap();
+            let path = format!("tmp/{}.db", uuid::Uuid::new_v4());
+            let db = BalanceDatabase::new(
+                &format!("sqlite://{}", path),
+                0,
+                &DatabaseOptions::default(),
+            )
+            .await;
+            TestDatabase { db, path }
+        }
+    }
+
+    impl Deref for TestDatabase {
+        type Target = BalanceDatabase;
+
+        fn deref(&self) -> &BalanceDatabase {
+            &self.db
+        }
+    }
+
+    impl Drop for TestDatabase {
+        fn drop(&mut self) {
+            // Also runs when a test fails, so don't panic again
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.path, suffix));
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_database() {
         std::fs::create_dir_all("tmp").unwrap();
         let test_file = format!("sqlite://tmp/{}.db", uuid::Uuid::new_v4());
-        let db = BalanceDatabase::new(&test_file, 0).await;
+        let db = BalanceDatabase::new(&test_file, 0, &DatabaseOptions::default()).await;
 
         db.init().await;
 
@@ -264,4 +1570,690 @@ mod tests {
 
         std::fs::remove_file(test_file.trim_start_matches("sqlite://")).unwrap();
     }
+
+    #[tokio::test]
+    async fn test_wallet_balances_and_tickers() {
+        let db = TestDatabase::new().await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.update_balance(1, "wallet1".to_string(), "ORDI".to_string(), 50)
+            .await;
+        assert_eq!(
+            db.get_wallet_balances("wallet1".to_string()).await,
+            vec![("BRC20".to_string(), 100), ("ORDI".to_string(), 50)]
+        );
+
+        db.add_ticker(
+            1,
+            "BRC20".to_string(),
+            "hash".to_string(),
+            "0x0000000000000000000000000000000000000001".to_string(),
+        )
+        .await;
+        let ticker = db.get_ticker("BRC20".to_string()).await.unwrap();
+        assert_eq!(
+            ticker.contract_address,
+            "0x0000000000000000000000000000000000000001"
+        );
+        assert_eq!(db.list_tickers().await, vec![ticker]);
+    }
+
+    #[tokio::test]
+    async fn test_balances_at() {
+        let db = TestDatabase::new().await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.update_balance(2, "wallet1".to_string(), "BRC20".to_string(), 40)
+            .await;
+        db.update_balance(2, "wallet2".to_string(), "BRC20".to_string(), 60)
+            .await;
+        db.update_balance(3, "wallet1".to_string(), "ORDI".to_string(), 5)
+            .await;
+        // Two updates in the same block, the later one wins
+        db.update_balance(3, "wallet2".to_string(), "BRC20".to_string(), 0)
+            .await;
+        db.update_balance(3, "wallet2".to_string(), "BRC20".to_string(), 10)
+            .await;
+
+        let balance_at = |wallet: &str, height| {
+            db.get_balance_at(wallet.to_string(), "BRC20".to_string(), height)
+        };
+        assert_eq!(balance_at("wallet1", 0).await, None);
+        assert_eq!(balance_at("wallet1", 1).await, Some(100));
+        assert_eq!(balance_at("wallet1", 2).await, Some(40));
+        assert_eq!(balance_at("wallet1", 10).await, Some(40));
+        assert_eq!(balance_at("wallet2", 3).await, Some(10));
+
+        assert_eq!(
+            db.get_wallet_balances_at("wallet1".to_string(), 2).await,
+            vec![("BRC20".to_string(), 40)]
+        );
+        assert_eq!(
+            db.get_wallet_balances_at("wallet1".to_string(), 3).await,
+            vec![("BRC20".to_string(), 40), ("ORDI".to_string(), 5)]
+        );
+
+        assert_eq!(
+            db.get_ticker_holders_at("BRC20".to_string(), 1).await,
+            vec![("wallet1".to_string(), 100)]
+        );
+        assert_eq!(
+            db.get_ticker_holders_at("BRC20".to_string(), 3).await,
+            vec![("wallet1".to_string(), 40), ("wallet2".to_string(), 10)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_top_holders() {
+        let db = TestDatabase::new().await;
+        // Lexicographic order would rank 9 above 10 and 100
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 9)
+            .await;
+        db.update_balance(1, "wallet2".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.update_balance(1, "wallet3".to_string(), "BRC20".to_string(), 10)
+            .await;
+        db.update_balance(1, "wallet4".to_string(), "BRC20".to_string(), 10)
+            .await;
+        db.update_balance(2, "wallet2".to_string(), "BRC20".to_string(), 0)
+            .await;
+
+        let page = db.get_top_holders("BRC20".to_string(), None, None, 2).await;
+        assert_eq!(page.total_supply, 29);
+        assert_eq!(
+            page.holders,
+            vec![
+                Holder {
+                    wallet: "wallet3".to_string(),
+                    amount: 10
+                },
+                Holder {
+                    wallet: "wallet4".to_string(),
+                    amount: 10
+                },
+            ]
+        );
+
+        let cursor = HolderCursor::decode(&page.next_cursor.unwrap().encode()).unwrap();
+        let page = db
+            .get_top_holders("BRC20".to_string(), None, Some(&cursor), 2)
+            .await;
+        assert_eq!(
+            page.holders,
+            vec![Holder {
+                wallet: "wallet1".to_string(),
+                amount: 9
+            }]
+        );
+        assert_eq!(page.next_cursor, None);
+
+        let page = db
+            .get_top_holders("BRC20".to_string(), Some(1), None, 10)
+            .await;
+        assert_eq!(page.total_supply, 129);
+        assert_eq!(page.percentage(10), 10.0 / 129.0 * 100.0);
+        assert_eq!(
+            page.holders.iter().map(|h| h.amount).collect::<Vec<_>>(),
+            vec![100, 10, 10, 9]
+        );
+
+        // The total supply is exact beyond the 53 bits of a float
+        db.update_balance(
+            3,
+            "wallet5".to_string(),
+            "BRC20".to_string(),
+            u128::MAX - 30,
+        )
+        .await;
+        let page = db.get_top_holders("BRC20".to_string(), None, None, 1).await;
+        assert_eq!(page.total_supply, u128::MAX - 1);
+        assert_eq!(page.percentage(0), 0.0);
+
+        // A ticker nobody holds
+        let page = db.get_top_holders("ORDI".to_string(), None, None, 1).await;
+        assert_eq!(page.total_supply, 0);
+        assert_eq!(page.percentage(0), 0.0);
+    }
+
+    #[test]
+    fn test_amount_encoding() {
+        assert_eq!(encode_amount(0), "0".repeat(AMOUNT_WIDTH));
+        assert_eq!(encode_amount(u128::MAX), u128::MAX.to_string());
+        assert!(encode_amount(9) < encode_amount(10));
+        assert_eq!(decode_amount(&encode_amount(12345)), Ok(12345));
+        assert_eq!(decode_amount(&encode_amount(u128::MAX)), Ok(u128::MAX));
+        assert!(decode_amount("100").is_err());
+        assert!(decode_amount("").is_err());
+        assert!(decode_amount(&format!("{}x", "0".repeat(AMOUNT_WIDTH - 1))).is_err());
+        assert!(decode_amount(&"9".repeat(AMOUNT_WIDTH)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_amount_migration() {
+        let db = TestDatabase::new().await;
+        assert_eq!(db.get_schema_version().await, Some(SCHEMA_VERSION));
+
+        // Simulate a database written before amounts were padded
+        sqlx::query("UPDATE brc20_prog_metadata SET value = '1' WHERE key = 'schema_version'")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO brc20_prog_current_balances (wallet, ticker, amount, block_height) VALUES ('wallet1', 'BRC20', '100', 1), ('wallet2', 'BRC20', '9', 1)")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO brc20_prog_historical_balances (block_height, wallet, ticker, amount) VALUES (1, 'wallet1', 'BRC20', '100'), (1, 'wallet2', 'BRC20', '9')")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+
+        db.init().await;
+        assert_eq!(db.get_schema_version().await, Some(SCHEMA_VERSION));
+        assert_eq!(
+            db.get_balance("wallet1".to_string(), "BRC20".to_string())
+                .await,
+            Some(100)
+        );
+        assert_eq!(
+            db.get_balance_at("wallet2".to_string(), "BRC20".to_string(), 1)
+                .await,
+            Some(9)
+        );
+        let page = db
+            .get_top_holders("BRC20".to_string(), None, None, 10)
+            .await;
+        assert_eq!(
+            page.holders.iter().map(|h| h.amount).collect::<Vec<_>>(),
+            vec![100, 9]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let db = TestDatabase::new().await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.update_balance(1, "wallet2".to_string(), "ORDI".to_string(), 7)
+            .await;
+        db.set_block_hash(1, "hash1".to_string()).await;
+        db.update_balance(2, "wallet1".to_string(), "BRC20".to_string(), 0)
+            .await;
+        db.update_balance(2, "wallet2".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.set_block_hash(2, "hash2".to_string()).await;
+
+        assert!(db.snapshot(Some(3)).await.is_err());
+
+        let mut snapshot = db.snapshot(None).await.unwrap();
+        // Writes after the snapshot was opened are not visible through it
+        db.update_balance(3, "wallet3".to_string(), "BRC20".to_string(), 1)
+            .await;
+        db.set_block_hash(3, "hash3".to_string()).await;
+        assert_eq!(snapshot.block_height, 2);
+        assert_eq!(snapshot.block_hash, Some("hash2".to_string()));
+        let balances: Vec<_> = snapshot.balances(None).try_collect().await.unwrap();
+        assert_eq!(
+            balances,
+            vec![
+                ("wallet2".to_string(), "BRC20".to_string(), 100),
+                ("wallet2".to_string(), "ORDI".to_string(), 7),
+            ]
+        );
+        // Random picks include balances that went back to zero
+        let mut sample = snapshot.random_balances(10, &[], &[]).await;
+        sample.sort();
+        assert_eq!(
+            sample,
+            vec![
+                ("wallet1".to_string(), "BRC20".to_string(), 0),
+                ("wallet2".to_string(), "BRC20".to_string(), 100),
+                ("wallet2".to_string(), "ORDI".to_string(), 7),
+            ]
+        );
+        assert_eq!(snapshot.random_balances(1, &[], &[]).await.len(), 1);
+        assert_eq!(snapshot.count_balances(&[], &[]).await, 3);
+        let brc20 = ["BRC20".to_string()];
+        assert_eq!(snapshot.count_balances(&brc20, &[]).await, 2);
+        drop(snapshot);
+
+        let mut snapshot = db.snapshot(Some(1)).await.unwrap();
+        assert_eq!(snapshot.block_hash, Some("hash1".to_string()));
+        let balances: Vec<_> = snapshot
+            .balances(Some("BRC20".to_string()))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            balances,
+            vec![("wallet1".to_string(), "BRC20".to_string(), 100)]
+        );
+        let mut sample = snapshot.random_balances(10, &[], &[]).await;
+        sample.sort();
+        assert_eq!(
+            sample,
+            vec![
+                ("wallet1".to_string(), "BRC20".to_string(), 100),
+                ("wallet2".to_string(), "ORDI".to_string(), 7),
+            ]
+        );
+        let wallet1 = ["wallet1".to_string()];
+        assert_eq!(snapshot.count_balances(&[], &wallet1).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_balances_page() {
+        let db = TestDatabase::new().await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.update_balance(1, "wallet2".to_string(), "ORDI".to_string(), 7)
+            .await;
+        db.set_block_hash(1, "hash1".to_string()).await;
+        db.update_balance(2, "wallet1".to_string(), "BRC20".to_string(), 0)
+            .await;
+        db.update_balance(2, "wallet2".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.set_block_hash(2, "hash2".to_string()).await;
+
+        let balance =
+            |wallet: &str, ticker: &str, amount| (wallet.to_string(), ticker.to_string(), amount);
+        let at_2 = vec![
+            balance("wallet1", "BRC20", 0),
+            balance("wallet2", "BRC20", 100),
+            balance("wallet2", "ORDI", 7),
+        ];
+        assert_eq!(db.balances_page(2, &[], &[], None, 10).await, at_2);
+        // Paging continues after the last key of the previous page
+        let first = db.balances_page(2, &[], &[], None, 2).await;
+        assert_eq!(first, at_2[..2]);
+        let after = Some(("wallet2".to_string(), "BRC20".to_string()));
+        assert_eq!(db.balances_page(2, &[], &[], after, 2).await, at_2[2..]);
+
+        // Pages keep reading height 2 while later blocks are indexed
+        db.update_balance(3, "wallet2".to_string(), "ORDI".to_string(), 1)
+            .await;
+        db.update_balance(3, "wallet3".to_string(), "BRC20".to_string(), 5)
+            .await;
+        db.set_block_hash(3, "hash3".to_string()).await;
+        assert_eq!(db.balances_page(2, &[], &[], None, 10).await, at_2);
+        assert_eq!(
+            db.balances_page(1, &["BRC20".to_string()], &[], None, 10)
+                .await,
+            vec![balance("wallet1", "BRC20", 100)]
+        );
+        assert_eq!(
+            db.balances_page(3, &[], &["wallet2".to_string()], None, 10)
+                .await,
+            vec![
+                balance("wallet2", "BRC20", 100),
+                balance("wallet2", "ORDI", 1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_changed_balance() {
+        let db = TestDatabase::new().await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.update_balance(2, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        assert_eq!(db.changed_balance(1).await, None);
+
+        db.update_balance(3, "wallet2".to_string(), "BRC20".to_string(), 0)
+            .await;
+        assert_eq!(db.changed_balance(1).await, None);
+
+        db.update_balance(3, "wallet1".to_string(), "BRC20".to_string(), 5)
+            .await;
+        let changed = Some(("wallet1".to_string(), "BRC20".to_string()));
+        assert_eq!(db.changed_balance(1).await, changed);
+        assert_eq!(db.changed_balance(3).await, None);
+
+        // Balances that didn't exist yet were 0
+        db.update_balance(4, "wallet3".to_string(), "ORDI".to_string(), 7)
+            .await;
+        let changed = Some(("wallet3".to_string(), "ORDI".to_string()));
+        assert_eq!(db.changed_balance(3).await, changed);
+    }
+
+    #[tokio::test]
+    async fn test_block_writer() {
+        let db = TestDatabase::new().await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.set_block_hash(1, "hash1".to_string()).await;
+
+        let mut block = db.begin_block(2).await;
+        block.add_ticker("ORDI", "0x01", "0xabc").await;
+        assert_eq!(
+            block.get_ticker_by_address("0xabc").await,
+            Some("ORDI".to_string())
+        );
+        block.update_balance("wallet1", "BRC20", 40).await;
+        assert_eq!(block.get_balance("wallet1", "BRC20").await, Some(40));
+
+        // Nothing of the open block is visible to readers, a snapshot stays at height 1
+        let mut snapshot = db.snapshot(None).await.unwrap();
+        assert_eq!(snapshot.block_height, 1);
+        let balances: Vec<_> = snapshot.balances(None).try_collect().await.unwrap();
+        assert_eq!(
+            balances,
+            vec![("wallet1".to_string(), "BRC20".to_string(), 100)]
+        );
+        drop(snapshot);
+        assert_eq!(db.get_ticker_by_address("0xabc".to_string()).await, None);
+
+        block.commit("hash2").await;
+        assert_eq!(db.get_last_block().await, 2);
+        assert_eq!(
+            db.get_balance("wallet1".to_string(), "BRC20".to_string())
+                .await,
+            Some(40)
+        );
+
+        // An interrupted block leaves nothing behind
+        let mut block = db.begin_block(3).await;
+        block.update_balance("wallet1", "BRC20", 0).await;
+        drop(block);
+        assert_eq!(db.get_last_block().await, 2);
+        assert_eq!(
+            db.get_balance("wallet1".to_string(), "BRC20".to_string())
+                .await,
+            Some(40)
+        );
+        assert_eq!(
+            db.get_balance_at("wallet1".to_string(), "BRC20".to_string(), 3)
+                .await,
+            Some(40)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let db = TestDatabase::new().await;
+        assert_eq!(db.get_metadata().await, None);
+
+        let metadata = DatabaseMetadata {
+            network: "signet".to_string(),
+            first_block: 230000,
+            controller_address: "0xc54dd4581af2dbf18e4d90840226756e9d2b3cdb".to_string(),
+            chain_id: 0x425243323073,
+            schema_version: SCHEMA_VERSION,
+        };
+        db.set_metadata(&metadata).await;
+        assert_eq!(db.get_metadata().await, Some(metadata.clone()));
+
+        let updated = DatabaseMetadata {
+            chain_id: 0x4252433230,
+            ..metadata
+        };
+        db.set_metadata(&updated).await;
+        assert_eq!(db.get_metadata().await, Some(updated));
+    }
+
+    #[tokio::test]
+    async fn test_reader_is_read_only() {
+        let db = TestDatabase::new().await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        assert_eq!(
+            db.get_balance("wallet1".to_string(), "BRC20".to_string())
+                .await,
+            Some(100)
+        );
+
+        let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&db.writer)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        let write = sqlx::query("DELETE FROM brc20_prog_current_balances")
+            .execute(&db.reader)
+            .await;
+        assert!(write.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let db = TestDatabase::new().await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.set_block_hash(1, "hash1".to_string()).await;
+
+        db.close().await;
+        // The checkpoint moved everything into the database file
+        let wal_size = std::fs::metadata(format!("{}-wal", db.path))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        assert_eq!(wal_size, 0);
+
+        let reopened = BalanceDatabase::new(
+            &format!("sqlite://{}", db.path),
+            0,
+            &DatabaseOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            reopened
+                .get_balance("wallet1".to_string(), "BRC20".to_string())
+                .await,
+            Some(100)
+        );
+        assert_eq!(reopened.get_last_block().await, 1);
+        reopened.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_rollback() {
+        let db = TestDatabase::new().await;
+        db.add_ticker(
+            1,
+            "BRC20".to_string(),
+            "hash".to_string(),
+            "0x01".to_string(),
+        )
+        .await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.set_block_hash(1, "hash1".to_string()).await;
+        db.add_ticker(
+            2,
+            "ORDI".to_string(),
+            "hash".to_string(),
+            "0x02".to_string(),
+        )
+        .await;
+        db.update_balance(2, "wallet1".to_string(), "BRC20".to_string(), 40)
+            .await;
+        db.update_balance(2, "wallet2".to_string(), "BRC20".to_string(), 60)
+            .await;
+        db.update_balance(2, "wallet2".to_string(), "ORDI".to_string(), 5)
+            .await;
+        db.set_block_hash(2, "hash2".to_string()).await;
+        db.update_balance(3, "wallet2".to_string(), "ORDI".to_string(), 7)
+            .await;
+        db.set_block_hash(3, "hash3".to_string()).await;
+
+        assert_eq!(
+            db.rollback_summary(1).await,
+            RollbackSummary {
+                block_height: 1,
+                blocks: 2,
+                balance_changes: 4,
+                current_balances: 3,
+                tickers: vec!["ORDI".to_string()],
+            }
+        );
+
+        db.reorg(2).await;
+        assert_eq!(db.get_last_block().await, 1);
+        assert_eq!(
+            db.get_wallet_balances("wallet1".to_string()).await,
+            vec![("BRC20".to_string(), 100)]
+        );
+        assert_eq!(db.get_wallet_balances("wallet2".to_string()).await, vec![]);
+        assert_eq!(db.get_ticker("ORDI".to_string()).await, None);
+        assert!(db.get_ticker("BRC20".to_string()).await.is_some());
+        assert_eq!(db.rollback_summary(1).await.blocks, 0);
+    }
+
+    #[tokio::test]
+    async fn test_rollback_same_height() {
+        let db = TestDatabase::new().await;
+        // Several changes in one block, the last one is the balance after it
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 40)
+            .await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 70)
+            .await;
+        db.set_block_hash(1, "hash1".to_string()).await;
+        db.update_balance(2, "wallet1".to_string(), "BRC20".to_string(), 0)
+            .await;
+        db.set_block_hash(2, "hash2".to_string()).await;
+
+        db.reorg(2).await;
+        assert_eq!(
+            db.get_balance("wallet1".to_string(), "BRC20".to_string())
+                .await,
+            Some(70)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writer_lease() {
+        let db = TestDatabase::new().await;
+
+        let timeout = Duration::from_secs(60);
+        let first = WriterLease::for_this_process();
+        let second = WriterLease::for_this_process();
+        assert_ne!(first.holder, second.holder);
+
+        db.acquire_writer_lease(&first, timeout).await.unwrap();
+        // Acquiring again is fine for the holder, not for anyone else
+        db.acquire_writer_lease(&first, timeout).await.unwrap();
+        let held = db.acquire_writer_lease(&second, timeout).await.unwrap_err();
+        assert_eq!(held.holder, first.holder);
+        assert_eq!(held.pid, std::process::id());
+        assert!(db.heartbeat_writer_lease(&first).await);
+        assert!(!db.heartbeat_writer_lease(&second).await);
+
+        // A lease without a recent heartbeat can be taken over
+        sqlx::query("UPDATE brc20_prog_writer_lease SET heartbeat_at = heartbeat_at - 120")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        db.acquire_writer_lease(&second, timeout).await.unwrap();
+        assert!(!db.heartbeat_writer_lease(&first).await);
+
+        // Releasing someone else's lease does nothing, reset keeps the lease
+        db.release_writer_lease(&first).await;
+        db.reset().await;
+        db.init().await;
+        assert_eq!(
+            db.get_writer_lease().await.map(|lease| lease.holder),
+            Some(second.holder.clone())
+        );
+        db.release_writer_lease(&second).await;
+        assert_eq!(db.get_writer_lease().await, None);
+        db.acquire_writer_lease(&first, timeout).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_check_schema() {
+        let db = TestDatabase::uninitialized().await;
+
+        // The lease comes first on a new database, nothing else is created before it
+        assert!(db.check_schema().await.is_err());
+        assert_eq!(db.get_writer_lease().await, None);
+        let lease = WriterLease::for_this_process();
+        db.acquire_writer_lease(&lease, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(!db.table_exists("brc20_prog_current_balances").await);
+        assert!(
+            db.check_schema()
+                .await
+                .unwrap_err()
+                .contains("not initialized")
+        );
+
+        db.init().await;
+        assert_eq!(db.check_schema().await, Ok(()));
+
+        sqlx::query("UPDATE brc20_prog_metadata SET value = '2' WHERE key = 'schema_version'")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        assert!(db.check_schema().await.unwrap_err().contains("older"));
+    }
+
+    #[tokio::test]
+    async fn test_repair_balance() {
+        let db = TestDatabase::new().await;
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.set_block_hash(1, "hash1".to_string()).await;
+        db.set_block_hash(2, "hash2".to_string()).await;
+
+        let repair = BalanceRepair {
+            block_height: 2,
+            block_hash: "hash2".to_string(),
+            wallet: "wallet1".to_string(),
+            ticker: "BRC20".to_string(),
+            database_amount: 100,
+            node_amount: 40,
+            repaired_at: 1000,
+        };
+        assert!(db.repair_balance(&repair).await);
+        assert_eq!(
+            db.get_balance("wallet1".to_string(), "BRC20".to_string())
+                .await,
+            Some(40)
+        );
+        // History before the repair is left alone
+        assert_eq!(
+            db.get_balance_at("wallet1".to_string(), "BRC20".to_string(), 1)
+                .await,
+            Some(100)
+        );
+        assert_eq!(
+            db.get_balance_at("wallet1".to_string(), "BRC20".to_string(), 2)
+                .await,
+            Some(40)
+        );
+        assert_eq!(db.get_balance_repairs().await, vec![repair.clone()]);
+
+        // A balance that changed since it was compared, or a height that isn't the indexed one
+        // anymore, is left alone
+        let stale = BalanceRepair {
+            node_amount: 50,
+            ..repair.clone()
+        };
+        assert!(!db.repair_balance(&stale).await);
+        let outdated = BalanceRepair {
+            block_height: 1,
+            database_amount: 40,
+            ..stale
+        };
+        assert!(!db.repair_balance(&outdated).await);
+        assert_eq!(
+            db.get_balance("wallet1".to_string(), "BRC20".to_string())
+                .await,
+            Some(40)
+        );
+        assert_eq!(db.get_balance_repairs().await, vec![repair.clone()]);
+
+        // A reorg below the repair reverts it, the audit row stays
+        db.reorg(2).await;
+        assert_eq!(
+            db.get_balance("wallet1".to_string(), "BRC20".to_string())
+                .await,
+            Some(100)
+        );
+        db.reset().await;
+        db.init().await;
+        assert_eq!(db.get_balance_repairs().await, vec![repair]);
+    }
 }
@@ -0,0 +1,406 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use arrow_array::{
+    ArrayRef, RecordBatch,
+    builder::{ArrayBuilder, Decimal256Builder, StringBuilder},
+};
+use arrow_buffer::i256;
+use arrow_schema::{DataType, Field, Schema};
+use futures::StreamExt;
+use parquet::{
+    arrow::ArrowWriter,
+    basic::Compression,
+    file::{metadata::KeyValue, properties::WriterProperties},
+};
+use serde_json::json;
+
+use crate::database::{AMOUNT_WIDTH, BalanceDatabase};
+
+/// Rows buffered before they are handed to the parquet writer
+const PARQUET_BATCH_SIZE: usize = 8192;
+/// Rows per parquet row group, bounds the writer's memory use
+const PARQUET_ROW_GROUP_SIZE: usize = 128 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" | "jsonl" => Ok(ExportFormat::Ndjson),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(format!(
+                "Unknown export format {}, expected csv, ndjson or parquet",
+                s
+            )),
+        }
+    }
+}
+
+pub struct ExportSummary {
+    pub rows: u64,
+    pub block_height: u64,
+    pub block_hash: Option<String>,
+}
+
+trait BalanceWriter {
+    fn write_row(&mut self, wallet: &str, ticker: &str, amount: u128)
+    -> Result<(), Box<dyn Error>>;
+
+    fn finish(self: Box<Self>) -> Result<(), Box<dyn Error>>;
+}
+
+/// Writes non-zero balances of all tickers, or only of `tickers`, at the indexed height or at
+/// `block_height`. Rows are streamed from a single read transaction, so memory use doesn't
+/// grow with the table and the header height matches every row.
+pub async fn export_balances(
+    database: &BalanceDatabase,
+    path: &Path,
+    format: ExportFormat,
+    tickers: &[String],
+    block_height: Option<u64>,
+) -> Result<ExportSummary, Box<dyn Error>> {
+    let mut snapshot = database.snapshot(block_height).await?;
+
+    // Write next to the target and rename at the end, so a failed export never looks complete
+    let mut partial_path = path.as_os_str().to_owned();
+    partial_path.push(".partial");
+    let partial_path = PathBuf::from(partial_path);
+    let file = BufWriter::new(File::create(&partial_path)?);
+    let block_hash = snapshot.block_hash.clone().unwrap_or_default();
+    let mut writer: Box<dyn BalanceWriter> = match format {
+        ExportFormat::Csv => Box::new(CsvWriter::new(file, snapshot.block_height, &block_hash)?),
+        ExportFormat::Ndjson => {
+            Box::new(NdjsonWriter::new(file, snapshot.block_height, &block_hash)?)
+        }
+        ExportFormat::Parquet => Box::new(ParquetWriter::new(
+            file,
+            snapshot.block_height,
+            &block_hash,
+        )?),
+    };
+
+    // Each ticker once, in the same order as an export of all tickers
+    let selections: Vec<Option<String>> = if tickers.is_empty() {
+        vec![None]
+    } else {
+        BTreeSet::from_iter(tickers)
+            .into_iter()
+            .cloned()
+            .map(Some)
+            .collect()
+    };
+
+    let mut rows = 0;
+    for ticker in selections {
+        let mut balances = snapshot.balances(ticker);
+        while let Some(balance) = balances.next().await {
+            let (wallet, ticker, amount) = balance?;
+            writer.write_row(&wallet, &ticker, amount)?;
+            rows += 1;
+        }
+    }
+    writer.finish()?;
+    std::fs::rename(&partial_path, path)?;
+
+    Ok(ExportSummary {
+        rows,
+        block_height: snapshot.block_height,
+        block_hash: snapshot.block_hash,
+    })
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+struct CsvWriter {
+    file: BufWriter<File>,
+}
+
+impl CsvWriter {
+    fn new(
+        mut file: BufWriter<File>,
+        block_height: u64,
+        block_hash: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        writeln!(file, "# block_height: {}", block_height)?;
+        writeln!(file, "# block_hash: {}", block_hash)?;
+        writeln!(file, "wallet,ticker,amount")?;
+        Ok(CsvWriter { file })
+    }
+}
+
+impl BalanceWriter for CsvWriter {
+    fn write_row(
+        &mut self,
+        wallet: &str,
+        ticker: &str,
+        amount: u128,
+    ) -> Result<(), Box<dyn Error>> {
+        writeln!(
+            self.file,
+            "{},{},{}",
+            csv_field(wallet),
+            csv_field(ticker),
+            amount
+        )?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+struct NdjsonWriter {
+    file: BufWriter<File>,
+}
+
+impl NdjsonWriter {
+    /// The first line holds the export metadata, every following line is one balance
+    fn new(
+        mut file: BufWriter<File>,
+        block_height: u64,
+        block_hash: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        writeln!(
+            file,
+            "{}",
+            json!({ "block_height": block_height, "block_hash": block_hash })
+        )?;
+        Ok(NdjsonWriter { file })
+    }
+}
+
+impl BalanceWriter for NdjsonWriter {
+    fn write_row(
+        &mut self,
+        wallet: &str,
+        ticker: &str,
+        amount: u128,
+    ) -> Result<(), Box<dyn Error>> {
+        writeln!(
+            self.file,
+            "{}",
+            json!({ "wallet": wallet, "ticker": ticker, "amount": amount.to_string() })
+        )?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+struct ParquetWriter {
+    writer: ArrowWriter<BufWriter<File>>,
+    schema: Arc<Schema>,
+    wallets: StringBuilder,
+    tickers: StringBuilder,
+    amounts: Decimal256Builder,
+}
+
+impl ParquetWriter {
+    /// Height and block hash are stored as key-value metadata in the file footer
+    fn new(
+        file: BufWriter<File>,
+        block_height: u64,
+        block_hash: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("wallet", DataType::Utf8, false),
+            Field::new("ticker", DataType::Utf8, false),
+            Field::new("amount", DataType::Decimal256(AMOUNT_WIDTH as u8, 0), false),
+        ]));
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(PARQUET_ROW_GROUP_SIZE)
+            .set_key_value_metadata(Some(vec![
+                KeyValue::new("block_height".to_string(), block_height.to_string()),
+                KeyValue::new("block_hash".to_string(), block_hash.to_string()),
+            ]))
+            .build();
+        Ok(ParquetWriter {
+            writer: ArrowWriter::try_new(file, schema.clone(), Some(properties))?,
+            schema,
+            wallets: StringBuilder::new(),
+            tickers: StringBuilder::new(),
+            amounts: Self::amount_builder()?,
+        })
+    }
+
+    fn amount_builder() -> Result<Decimal256Builder, Box<dyn Error>> {
+        Ok(Decimal256Builder::with_capacity(PARQUET_BATCH_SIZE)
+            .with_precision_and_scale(AMOUNT_WIDTH as u8, 0)?)
+    }
+
+    fn flush_batch(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.wallets.is_empty() {
+            return Ok(());
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.wallets.finish()),
+            Arc::new(self.tickers.finish()),
+            Arc::new(std::mem::replace(&mut self.amounts, Self::amount_builder()?).finish()),
+        ];
+        self.writer
+            .write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+        Ok(())
+    }
+}
+
+impl BalanceWriter for ParquetWriter {
+    fn write_row(
+        &mut self,
+        wallet: &str,
+        ticker: &str,
+        amount: u128,
+    ) -> Result<(), Box<dyn Error>> {
+        self.wallets.append_value(wallet);
+        self.tickers.append_value(ticker);
+        self.amounts.append_value(i256::from_parts(amount, 0));
+        if self.wallets.len() >= PARQUET_BATCH_SIZE {
+            self.flush_batch()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        self.flush_batch()?;
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+    use crate::database::tests::TestDatabase;
+
+    #[tokio::test]
+    async fn test_export() {
+        let db = TestDatabase::new().await;
+        let id = uuid::Uuid::new_v4();
+        db.update_balance(1, "wallet1".to_string(), "BRC20".to_string(), 100)
+            .await;
+        db.update_balance(1, "wallet2".to_string(), "a,b".to_string(), 5)
+            .await;
+        db.set_block_hash(1, "hash1".to_string()).await;
+        db.update_balance(2, "wallet1".to_string(), "BRC20".to_string(), 0)
+            .await;
+        db.set_block_hash(2, "hash2".to_string()).await;
+
+        let csv_path = format!("tmp/{}.csv", id);
+        let summary = export_balances(&db, Path::new(&csv_path), ExportFormat::Csv, &[], None)
+            .await
+            .unwrap();
+        assert_eq!(summary.rows, 1);
+        assert_eq!(
+            std::fs::read_to_string(&csv_path).unwrap(),
+            "# block_height: 2\n# block_hash: hash2\nwallet,ticker,amount\nwallet2,\"a,b\",5\n"
+        );
+
+        let ndjson_path = format!("tmp/{}.ndjson", id);
+        let summary = export_balances(
+            &db,
+            Path::new(&ndjson_path),
+            ExportFormat::Ndjson,
+            &["BRC20".to_string(), "BRC20".to_string()],
+            Some(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.rows, 1);
+        assert_eq!(
+            std::fs::read_to_string(&ndjson_path).unwrap(),
+            "{\"block_hash\":\"hash1\",\"block_height\":1}\n{\"amount\":\"100\",\"ticker\":\"BRC20\",\"wallet\":\"wallet1\"}\n"
+        );
+
+        let parquet_path = format!("tmp/{}.parquet", id);
+        let summary = export_balances(
+            &db,
+            Path::new(&parquet_path),
+            ExportFormat::Parquet,
+            &[],
+            Some(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.rows, 2);
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(&parquet_path).unwrap()).unwrap();
+        let metadata: Vec<_> = reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap()
+            .iter()
+            .map(|kv| (kv.key.as_str(), kv.value.as_deref().unwrap()))
+            .collect();
+        assert!(metadata.contains(&("block_height", "1")));
+        assert!(metadata.contains(&("block_hash", "hash1")));
+        let batches = reader
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        let strings = |column: usize| {
+            batch
+                .column(column)
+                .as_any()
+                .downcast_ref::<arrow_array::StringArray>()
+                .unwrap()
+                .iter()
+                .map(|value| value.unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let amounts = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<arrow_array::Decimal256Array>()
+            .unwrap();
+        assert_eq!(strings(0), vec!["wallet1", "wallet2"]);
+        assert_eq!(strings(1), vec!["BRC20", "a,b"]);
+        assert_eq!(
+            amounts.iter().collect::<Vec<_>>(),
+            vec![Some(i256::from(100)), Some(i256::from(5))]
+        );
+
+        // The partial file is named after the whole target, so exports to the same name with
+        // other extensions don't share it
+        for path in [&csv_path, &ndjson_path, &parquet_path] {
+            assert!(!Path::new(&format!("{}.partial", path)).exists());
+        }
+
+        for path in [&csv_path, &ndjson_path, &parquet_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
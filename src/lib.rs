@@ -0,0 +1,12 @@
+pub mod config;
+pub mod database;
+pub mod export;
+pub mod limit;
+pub mod metrics;
+pub mod retry;
+pub mod rpc;
+pub mod server;
+pub mod shutdown;
+pub mod status;
+pub mod tracker;
+pub mod verify;
@@ -0,0 +1,317 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics::METRICS;
+
+/// Limits for one class of requests to one endpoint, unset fields don't limit
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limit {
+    /// Requests per second
+    pub rate: Option<f64>,
+    /// Requests that can be sent at once after a quiet period [default: rate rounded up]
+    pub burst: Option<u32>,
+    pub max_in_flight: Option<usize>,
+}
+
+impl Limit {
+    /// Fills the unset fields from `defaults`
+    pub fn or(self, defaults: Limit) -> Limit {
+        Limit {
+            rate: self.rate.or(defaults.rate),
+            burst: self.burst.or(defaults.burst),
+            max_in_flight: self.max_in_flight.or(defaults.max_in_flight),
+        }
+    }
+}
+
+/// Limits of one endpoint. Every request counts against `total`, and against the limit of
+/// its method class if it has one.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EndpointLimits {
+    pub total: Limit,
+    /// `eth_getLogs`
+    pub logs: Limit,
+    /// `eth_getBlockByNumber`, `eth_getBlockByHash` and `eth_blockNumber`
+    pub blocks: Limit,
+    /// `eth_call`
+    pub call: Limit,
+}
+
+impl EndpointLimits {
+    pub fn or(self, defaults: EndpointLimits) -> EndpointLimits {
+        EndpointLimits {
+            total: self.total.or(defaults.total),
+            logs: self.logs.or(defaults.logs),
+            blocks: self.blocks.or(defaults.blocks),
+            call: self.call.or(defaults.call),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MethodClass {
+    Logs,
+    Blocks,
+    Call,
+}
+
+impl MethodClass {
+    pub fn of(method: &str) -> Option<Self> {
+        match method {
+            "eth_getLogs" => Some(MethodClass::Logs),
+            "eth_getBlockByNumber" | "eth_getBlockByHash" | "eth_blockNumber" => {
+                Some(MethodClass::Blocks)
+            }
+            "eth_call" => Some(MethodClass::Call),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            MethodClass::Logs => "logs",
+            MethodClass::Blocks => "blocks",
+            MethodClass::Call => "call",
+        }
+    }
+}
+
+/// Runtime state of `EndpointLimits`
+pub struct EndpointLimiter {
+    total: Limiter,
+    logs: Limiter,
+    blocks: Limiter,
+    call: Limiter,
+}
+
+/// Permits of a request, released when it is dropped
+pub struct Permits(#[allow(dead_code)] Vec<OwnedSemaphorePermit>);
+
+impl EndpointLimiter {
+    pub fn new(limits: &EndpointLimits) -> Self {
+        EndpointLimiter {
+            total: Limiter::new(&limits.total),
+            logs: Limiter::new(&limits.logs),
+            blocks: Limiter::new(&limits.blocks),
+            call: Limiter::new(&limits.call),
+        }
+    }
+
+    fn class(&self, class: MethodClass) -> &Limiter {
+        match class {
+            MethodClass::Logs => &self.logs,
+            MethodClass::Blocks => &self.blocks,
+            MethodClass::Call => &self.call,
+        }
+    }
+
+    /// Waits until a request to `method` may be sent
+    pub async fn acquire(&self, method: &str) -> Permits {
+        self.acquire_batch([method]).await
+    }
+
+    /// Waits until all of `methods` may be sent in one batch. Each method takes a token from
+    /// the rate limits, while the batch takes one in-flight slot per class.
+    pub async fn acquire_batch<'a>(&self, methods: impl IntoIterator<Item = &'a str>) -> Permits {
+        let mut counts = BTreeMap::new();
+        let mut total = 0;
+        for method in methods {
+            if let Some(class) = MethodClass::of(method) {
+                *counts.entry(class).or_insert(0) += 1;
+            }
+            total += 1;
+        }
+
+        // Classes are always taken in the same order and before the total, so concurrent
+        // requests can't wait on each other's permits
+        let mut permits = Vec::new();
+        for (class, count) in counts {
+            permits.extend(self.class(class).acquire(count, class.label()).await);
+        }
+        permits.extend(self.total.acquire(total, "total").await);
+        Permits(permits)
+    }
+}
+
+struct Limiter {
+    bucket: Option<Mutex<TokenBucket>>,
+    in_flight: Option<Arc<Semaphore>>,
+}
+
+impl Limiter {
+    fn new(limit: &Limit) -> Self {
+        Limiter {
+            bucket: limit.rate.map(|rate| {
+                let burst = limit.burst.unwrap_or(rate.ceil() as u32).max(1);
+                Mutex::new(TokenBucket::new(rate, burst))
+            }),
+            in_flight: limit
+                .max_in_flight
+                .map(|max_in_flight| Arc::new(Semaphore::new(max_in_flight.max(1)))),
+        }
+    }
+
+    async fn acquire(&self, tokens: u32, label: &str) -> Option<OwnedSemaphorePermit> {
+        let mut throttled = false;
+        let permit = match &self.in_flight {
+            Some(semaphore) => Some(match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    throttled = true;
+                    semaphore.clone().acquire_owned().await.unwrap()
+                }
+            }),
+            None => None,
+        };
+        if let Some(bucket) = &self.bucket {
+            loop {
+                let wait = bucket.lock().unwrap().take(tokens, Instant::now());
+                match wait {
+                    Some(wait) => {
+                        throttled = true;
+                        tokio::time::sleep(wait).await;
+                    }
+                    None => break,
+                }
+            }
+        }
+        if throttled {
+            METRICS.rpc_throttled.with_label_values(&[label]).inc();
+        }
+        permit
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32) -> Self {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes `tokens`, or returns how long to wait until they are available. Batches larger
+    /// than the burst only wait for a full bucket so they don't wait forever, the tokens they
+    /// take beyond it are owed and delay the requests after them.
+    fn take(&mut self, tokens: u32, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+
+        let needed = (tokens as f64).min(self.burst);
+        if self.tokens >= needed {
+            self.tokens -= tokens as f64;
+            None
+        } else {
+            Some(Duration::from_secs_f64((needed - self.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2);
+        bucket.updated = start;
+        assert_eq!(bucket.take(1, start), None);
+        assert_eq!(bucket.take(1, start), None);
+        assert_eq!(bucket.take(1, start), Some(Duration::from_millis(100)));
+        assert_eq!(bucket.take(1, start + Duration::from_millis(100)), None);
+
+        // Refills up to the burst only, and a batch larger than it goes into debt that the
+        // next request waits out
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(5, later), None);
+        assert_eq!(bucket.take(1, later), Some(Duration::from_millis(400)));
+        assert_eq!(
+            bucket.take(5, later + Duration::from_millis(300)),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(bucket.take(5, later + Duration::from_millis(500)), None);
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_limiter() {
+        let limiter = EndpointLimiter::new(&EndpointLimits {
+            total: Limit {
+                max_in_flight: Some(2),
+                ..Default::default()
+            },
+            logs: Limit {
+                max_in_flight: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let logs = limiter.acquire("eth_getLogs").await;
+        assert_eq!(logs.0.len(), 2);
+        // The logs slot is taken, other classes still go through
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), limiter.acquire("eth_getLogs"))
+                .await
+                .is_err()
+        );
+        let call = limiter.acquire("eth_call").await;
+        assert_eq!(call.0.len(), 1);
+        // Both total slots are taken
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), limiter.acquire("eth_chainId"))
+                .await
+                .is_err()
+        );
+        drop(logs);
+        assert_eq!(limiter.acquire("eth_chainId").await.0.len(), 1);
+
+        let batch = limiter
+            .acquire_batch(["eth_call", "eth_call", "eth_getLogs"])
+            .await;
+        assert_eq!(batch.0.len(), 2);
+    }
+
+    #[test]
+    fn test_limits_or() {
+        let defaults = EndpointLimits {
+            total: Limit {
+                rate: Some(50.0),
+                max_in_flight: Some(16),
+                ..Default::default()
+            },
+            call: Limit {
+                rate: Some(100.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let limits = EndpointLimits {
+            total: Limit {
+                rate: Some(5.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .or(defaults);
+        assert_eq!(limits.total.rate, Some(5.0));
+        assert_eq!(limits.total.max_in_flight, Some(16));
+        assert_eq!(limits.call.rate, Some(100.0));
+        assert_eq!(limits.logs, Limit::default());
+    }
+}
@@ -1,100 +1,524 @@
-use base64::{Engine, prelude::BASE64_STANDARD};
+use std::{io::Write, time::Duration};
+
+use brc20_prog::Brc20ProgApiClient;
+use clap::Parser;
 use dotenvy::dotenv;
-use jsonrpsee::http_client::HttpClientBuilder;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-use crate::{
-    database::BalanceDatabase,
+use brc20_prog_balance_tracker::{
+    database::{BalanceDatabase, HolderCursor, WriterLease},
+    export::export_balances,
+    metrics::unix_time,
+    rpc::RpcPool,
+    server,
+    shutdown::Shutdown,
+    status,
     tracker::{BalanceTracker, TestStatus},
+    verify::{VerificationReport, VerifyOptions, write_json, write_junit},
 };
 
-mod database;
-mod tracker;
+mod cli;
 
-pub struct Args {
-    db_url: String,
-    rpc_url: String,
-    rpc_user: String,
-    rpc_password: String,
-    network: String,
-}
+/// Exit status after a shutdown that had to roll back the block being indexed
+const EXIT_INTERRUPTED: i32 = 2;
+/// Exit status after another process took over the writer lease
+const EXIT_LEASE_LOST: i32 = 3;
+/// How long verification waits for the node tip and the indexed height to line up again
+const VERIFY_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Verification attempts before giving up on the node tip and the indexed height lining up
+const VERIFY_ATTEMPTS: u32 = 6;
+
+use cli::{Cli, Command, ExportArgs, QueryCommand, RollbackArgs, VerifyArgs};
 
-fn parse_env() -> Args {
-    // Placeholder for argument parsing logic
-    // Parse rpc-url, rpc-user, rpc-password and network type (mainnet, signet)
-    let db_url =
-        std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://balances.sqlite".into());
-    let rpc_url = std::env::var("RPC_URL").unwrap_or_else(|_| "http://localhost:18545".into());
-    let rpc_user = std::env::var("RPC_USER").unwrap_or_else(|_| "user".into());
-    let rpc_password = std::env::var("RPC_PASSWORD").unwrap_or_else(|_| "password".into());
-    let network = std::env::var("NETWORK").unwrap_or_else(|_| "mainnet".into());
-
-    Args {
-        rpc_url,
-        rpc_user,
-        rpc_password,
-        network,
-        db_url,
+/// Logs go to stdout, filtered by `RUST_LOG` such as
+/// `info,brc20_prog_balance_tracker::tracker=debug`
+fn init_logging(format: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        "json" => builder.json().init(),
+        _ => builder.init(),
     }
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    let env = parse_env();
+    let cli = Cli::parse();
+    let config_file = cli
+        .global
+        .config_file()
+        .unwrap_or_else(|err| panic!("{}", err));
+    let settings = cli
+        .global
+        .resolve(&config_file)
+        .unwrap_or_else(|err| panic!("{}", err));
+    init_logging(&settings.log_format);
 
-    println!("Database URL: {}", env.db_url);
-    println!("RPC URL: {}", env.rpc_url);
-    println!("Network: {}", env.network);
+    info!(
+        database_url = %settings.db_url,
+        rpc_urls = %settings.rpc.urls.join(","),
+        rpc_quorum = settings.rpc.quorum,
+        network = %settings.network.name,
+        first_block = settings.network.first_block,
+        "Starting"
+    );
 
-    let first_block = match env.network.as_str() {
-        "mainnet" => 912690,
-        "signet" => 230000,
-        _ => 0,
+    let command = cli.command.unwrap_or(Command::Run(cli.run));
+    // These never take the writer lease, so they must not create or migrate anything
+    let read_only = match &command {
+        Command::Status | Command::Query(_) | Command::Export(_) => true,
+        Command::Verify(verify_args) => !verify_args.heal,
+        Command::Run(_) | Command::Reset | Command::Rollback(_) => false,
     };
+    if read_only && !BalanceDatabase::exists(&settings.db_url).await {
+        panic!(
+            "Database {} does not exist, run the tracker first",
+            settings.db_url
+        );
+    }
 
-    if std::env::args().any(|arg| arg == "--reset") {
-        let db = BalanceDatabase::new(&env.db_url, first_block).await;
-        db.reset().await;
-        db.init().await;
-        println!("Database reset complete.");
-        return;
+    let database = BalanceDatabase::new(
+        &settings.db_url,
+        settings.network.first_block as i64,
+        &settings.db_options,
+    )
+    .await;
+    if read_only {
+        database
+            .check_schema()
+            .await
+            .unwrap_or_else(|err| panic!("{}", err));
     }
 
-    let tracker = BalanceTracker::new(
-        BalanceDatabase::new(&env.db_url, first_block).await,
-        HttpClientBuilder::new()
-            .set_headers({
-                let mut headers = http::HeaderMap::new();
-                let auth_value = format!(
-                    "Basic {}",
-                    BASE64_STANDARD.encode(format!("{}:{}", env.rpc_user, env.rpc_password))
-                );
-                headers.insert(
-                    http::header::AUTHORIZATION,
-                    http::HeaderValue::from_str(&auth_value).unwrap(),
-                );
-                headers
-            })
-            .build(env.rpc_url)
-            .unwrap(),
+    match command {
+        Command::Run(run_args) => {
+            let lease = acquire_writer_lease(&database, settings.writer_lease_timeout).await;
+            let heartbeat = spawn_lease_heartbeat(
+                database.clone(),
+                lease.clone(),
+                settings.writer_lease_timeout,
+            );
+            let client = settings.rpc.client();
+            let tracker =
+                BalanceTracker::new(database.clone(), client.clone(), settings.network.clone());
+
+            tracker
+                .check_consistency()
+                .await
+                .expect("Database consistency check failed");
+            tracker.record_metadata().await;
+
+            let _server_handle = match run_args.server_addr(&config_file) {
+                Some(addr) => Some(
+                    server::start(&addr, database.clone())
+                        .await
+                        .expect("Failed to start query server"),
+                ),
+                None => None,
+            };
+
+            if let Some(addr) = run_args.status_addr(&config_file) {
+                status::start(
+                    &addr,
+                    database.clone(),
+                    client,
+                    run_args.readiness(&config_file),
+                )
+                .await
+                .expect("Failed to start status server");
+            }
+
+            if run_args.verify {
+                verify(&tracker, &[VerifyOptions::default()], None)
+                    .await
+                    .unwrap_or_else(|err| panic!("{}", err));
+            }
+
+            let shutdown = Shutdown::listen();
+            let timeout = run_args.shutdown_timeout(&config_file);
+            // The tracker stops by itself after the block it is indexing, unless that takes
+            // longer than the timeout or a second signal arrives
+            let background_verification = run_args.background_verification(&config_file);
+            let finished = tokio::select! {
+                _ = async {
+                    tokio::join!(tracker.run(&shutdown), async {
+                        if let Some((interval, options)) = &background_verification {
+                            tracker
+                                .verify_in_background(*interval, options, &shutdown)
+                                .await;
+                        }
+                    })
+                } => true,
+                _ = async {
+                    shutdown.requested().await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(timeout) => {}
+                        _ = shutdown.forced() => {}
+                    }
+                } => false,
+            };
+            if !finished {
+                warn!("Current block interrupted, its writes are rolled back");
+            }
+            let height = database.get_last_block().await;
+            heartbeat.abort();
+            database.release_writer_lease(&lease).await;
+            database.close().await;
+            info!(height, "Shutdown complete");
+            std::process::exit(if finished { 0 } else { EXIT_INTERRUPTED });
+        }
+        Command::Verify(verify_args) => {
+            let tracker = BalanceTracker::new(
+                database.clone(),
+                settings.rpc.client(),
+                settings.network.clone(),
+            );
+            // Healing writes to the database, so it must not run next to a tracker
+            let lease = if verify_args.heal {
+                Some(acquire_writer_lease(&database, settings.writer_lease_timeout).await)
+            } else {
+                None
+            };
+            tracker
+                .check_consistency()
+                .await
+                .expect("Database consistency check failed");
+            let heartbeat = lease.as_ref().map(|lease| {
+                spawn_lease_heartbeat(
+                    database.clone(),
+                    lease.clone(),
+                    settings.writer_lease_timeout,
+                )
+            });
+            let result = match verify_args
+                .options(database.first_block(), database.get_last_block().await)
+            {
+                Ok(options) => verify(&tracker, &options, Some(&verify_args)).await,
+                Err(err) => Err(err),
+            };
+            if let (Some(lease), Some(heartbeat)) = (lease, heartbeat) {
+                heartbeat.abort();
+                database.release_writer_lease(&lease).await;
+            }
+            result.unwrap_or_else(|err| panic!("{}", err));
+        }
+        Command::Reset => {
+            let lease = acquire_writer_lease(&database, settings.writer_lease_timeout).await;
+            database.reset().await;
+            database.init().await;
+            database.release_writer_lease(&lease).await;
+            info!("Database reset complete.");
+        }
+        Command::Rollback(rollback_args) => {
+            let lease = acquire_writer_lease(&database, settings.writer_lease_timeout).await;
+            rollback(&database, rollback_args, &lease).await;
+            database.release_writer_lease(&lease).await;
+        }
+        Command::Status => print_status(&database, &settings.rpc.client()).await,
+        Command::Query(query) => run_query(&database, query).await,
+        Command::Export(export_args) => export(&database, export_args).await,
+    }
+}
+
+/// Takes the writer lease, panics if another process is writing to the database. The
+/// tables are created or migrated only once the lease is held.
+async fn acquire_writer_lease(database: &BalanceDatabase, timeout: Duration) -> WriterLease {
+    let lease = WriterLease::for_this_process();
+    if let Err(holder) = database.acquire_writer_lease(&lease, timeout).await {
+        panic!(
+            "Database is in use by process {} on {}, last seen {}s ago. Stop it first, or wait {}s if it is gone",
+            holder.pid,
+            holder.hostname,
+            unix_time() - holder.heartbeat_at,
+            timeout.as_secs()
+        );
+    }
+    info!(
+        holder = %lease.holder,
+        hostname = %lease.hostname,
+        pid = lease.pid,
+        "Acquired writer lease"
     );
+    database.init().await;
+    lease
+}
 
-    if std::env::args().any(|arg| arg == "--test") {
+/// Keeps the lease alive, and exits right away if another process took it over, as that
+/// process is now writing to the database too
+fn spawn_lease_heartbeat(
+    database: BalanceDatabase,
+    lease: WriterLease,
+    timeout: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(timeout / 4);
         loop {
-            match tracker.test().await.expect("Test failed") {
-                TestStatus::Passed => {
-                    println!("All tests passed!");
-                    break;
+            interval.tick().await;
+            if !database.heartbeat_writer_lease(&lease).await {
+                error!("Writer lease was taken over by another process, stopping");
+                std::process::exit(EXIT_LEASE_LOST);
+            }
+        }
+    })
+}
+
+/// Verifies at every height of `options`, heals mismatches if asked to and writes the
+/// requested reports. Fails if any balance still differs from the node.
+async fn verify(
+    tracker: &BalanceTracker,
+    options: &[VerifyOptions],
+    reports: Option<&VerifyArgs>,
+) -> Result<(), String> {
+    let heal = reports.is_some_and(|args| args.heal);
+    let mut results = Vec::new();
+    for options in options {
+        let mut attempt = 1;
+        let mut report = loop {
+            let status = tracker
+                .test(options)
+                .await
+                .map_err(|err| format!("Test failed: {}", err))?;
+            match status {
+                TestStatus::Finished(report) => break report,
+                TestStatus::NeedsRetry if attempt < VERIFY_ATTEMPTS => {
+                    info!(attempt, "Tests need retry, waiting...");
+                    tokio::time::sleep(VERIFY_RETRY_INTERVAL).await;
+                    attempt += 1;
                 }
                 TestStatus::NeedsRetry => {
-                    println!("Tests need retry, waiting...");
+                    return Err(format!(
+                        "Test failed: the node tip didn't stay at the indexed height in {} attempts, make sure the tracker has caught up",
+                        VERIFY_ATTEMPTS
+                    ));
+                }
+            }
+        };
+        if heal {
+            tracker
+                .heal(&mut report)
+                .await
+                .map_err(|err| format!("Healing failed: {}", err))?;
+        }
+        results.push(report);
+    }
+    if let Some(path) = reports.and_then(|args| args.json_report.as_ref()) {
+        write_json(&results, path)
+            .map_err(|err| format!("Failed to write the JSON report: {}", err))?;
+    }
+    if let Some(path) = reports.and_then(|args| args.junit_report.as_ref()) {
+        write_junit(&results, path)
+            .map_err(|err| format!("Failed to write the JUnit report: {}", err))?;
+    }
+
+    let unhealed = |report: &VerificationReport| {
+        report
+            .mismatches
+            .iter()
+            .filter(|mismatch| !mismatch.healed)
+            .count()
+    };
+    let heights: Vec<String> = results
+        .iter()
+        .filter(|report| unhealed(report) > 0)
+        .map(|report| report.block_height.to_string())
+        .collect();
+    if !heights.is_empty() {
+        return Err(format!(
+            "{} of {} balances differ from the node, at blocks {}",
+            results.iter().map(unhealed).sum::<usize>(),
+            results.iter().map(|report| report.checked()).sum::<u64>(),
+            heights.join(", ")
+        ));
+    }
+    let healed: usize = results.iter().map(|report| report.mismatches.len()).sum();
+    if healed > 0 {
+        warn!(
+            healed,
+            "Every mismatching balance was healed, see brc20_prog_balance_repairs"
+        );
+    } else {
+        info!("All tests passed!");
+    }
+    Ok(())
+}
+
+async fn rollback(database: &BalanceDatabase, args: RollbackArgs, lease: &WriterLease) {
+    let indexed_height = database.get_last_block().await;
+    if args.to < database.first_block() {
+        panic!(
+            "Block {} is before the first block {}, use reset to start over",
+            args.to,
+            database.first_block()
+        );
+    }
+    if database.get_block_hash(args.to).await.is_none() || args.to >= indexed_height {
+        println!(
+            "Nothing to roll back, indexed height is {} and block {} is not below it",
+            indexed_height, args.to
+        );
+        return;
+    }
 
-                    // Wait for a while before retrying
-                    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+    let summary = database.rollback_summary(args.to).await;
+    println!(
+        "Rolling back from block {} to block {} reverts:",
+        indexed_height, summary.block_height
+    );
+    println!("  {} indexed blocks", summary.blocks);
+    println!("  {} balance changes", summary.balance_changes);
+    println!(
+        "  {} current balances, restored to their value at block {} or removed",
+        summary.current_balances, summary.block_height
+    );
+    if summary.tickers.is_empty() {
+        println!("  no tickers");
+    } else {
+        println!(
+            "  {} tickers: {}",
+            summary.tickers.len(),
+            summary.tickers.join(", ")
+        );
+    }
+
+    if !args.yes {
+        print!("Type 'yes' to continue: ");
+        std::io::stdout().flush().unwrap();
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).unwrap();
+        if answer.trim() != "yes" {
+            database.release_writer_lease(lease).await;
+            println!("Rollback aborted.");
+            std::process::exit(1);
+        }
+    }
+
+    database.reorg(args.to + 1).await;
+    println!(
+        "Rolled back to block {}, indexing resumes from block {}",
+        args.to,
+        args.to + 1
+    );
+}
+
+async fn print_status(database: &BalanceDatabase, client: &RpcPool) {
+    let indexed_height = database.get_last_block().await;
+    match database.get_metadata().await {
+        Some(metadata) => {
+            println!("Network: {}", metadata.network);
+            println!("Chain id: 0x{:x}", metadata.chain_id);
+            println!("First block: {}", metadata.first_block);
+            println!("Controller: {}", metadata.controller_address);
+        }
+        None => println!("Network: not recorded yet"),
+    }
+    println!(
+        "Schema version: {}",
+        database.get_schema_version().await.unwrap_or(1)
+    );
+    match database.get_writer_lease().await {
+        Some(lease) => println!(
+            "Writer: process {} on {}, last seen {}s ago",
+            lease.pid,
+            lease.hostname,
+            unix_time() - lease.heartbeat_at
+        ),
+        None => println!("Writer: none"),
+    }
+    match database.get_block_hash(indexed_height).await {
+        Some(hash) => println!("Indexed height: {} ({})", indexed_height, hash),
+        None => println!("Indexed height: none"),
+    }
+    match client.eth_block_number().await {
+        Ok(tip) => {
+            let tip = u64::from_str_radix(tip.trim_start_matches("0x"), 16).unwrap_or_default();
+            println!("Node tip: {}", tip);
+            println!("Lag: {}", tip.saturating_sub(indexed_height));
+        }
+        Err(err) => println!("Node tip: unavailable ({})", err),
+    }
+}
+
+async fn run_query(database: &BalanceDatabase, query: QueryCommand) {
+    match query {
+        QueryCommand::Balance {
+            wallet,
+            ticker,
+            height,
+        } => {
+            let wallet = wallet.to_lowercase();
+            let balance = match height {
+                Some(height) => {
+                    database
+                        .get_balance_at(wallet.clone(), ticker.clone(), height)
+                        .await
+                }
+                None => database.get_balance(wallet.clone(), ticker.clone()).await,
+            };
+            println!("{} {} {}", wallet, ticker, balance.unwrap_or(0));
+        }
+        QueryCommand::WalletBalances { wallet, height } => {
+            let wallet = wallet.to_lowercase();
+            let balances = match height {
+                Some(height) => {
+                    database
+                        .get_wallet_balances_at(wallet.clone(), height)
+                        .await
                 }
+                None => database.get_wallet_balances(wallet.clone()).await,
+            };
+            for (ticker, balance) in balances {
+                println!("{} {} {}", wallet, ticker, balance);
+            }
+        }
+        QueryCommand::Holders { ticker, height } => {
+            for (wallet, balance) in database.get_ticker_holders_at(ticker.clone(), height).await {
+                println!("{} {} {}", wallet, ticker, balance);
+            }
+        }
+        QueryCommand::TopHolders {
+            ticker,
+            limit,
+            height,
+            cursor,
+        } => {
+            let cursor =
+                cursor.map(|cursor| HolderCursor::decode(&cursor).expect("Invalid cursor"));
+            let page = database
+                .get_top_holders(ticker, height, cursor.as_ref(), limit)
+                .await;
+            println!("Total supply: {}", page.total_supply);
+            for holder in &page.holders {
+                println!(
+                    "{} {} {:.4}%",
+                    holder.wallet,
+                    holder.amount,
+                    page.percentage(holder.amount)
+                );
+            }
+            if let Some(cursor) = page.next_cursor {
+                println!("Next cursor: {}", cursor.encode());
             }
         }
     }
+}
 
-    tracker.run().await;
+async fn export(database: &BalanceDatabase, args: ExportArgs) {
+    let summary = export_balances(
+        database,
+        &args.path,
+        args.format,
+        &args.tickers,
+        args.height,
+    )
+    .await
+    .expect("Export failed");
+    println!(
+        "Exported {} balances at block {} ({}) to {}",
+        summary.rows,
+        summary.block_height,
+        summary.block_hash.unwrap_or_default(),
+        args.path.display()
+    );
 }
@@ -0,0 +1,347 @@
+use std::{
+    collections::VecDeque,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Window used to compute `blocks_per_second`
+const BLOCK_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Process-wide metrics, served in the Prometheus text format by the status server
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub indexed_height: IntGauge,
+    pub tip_height: IntGauge,
+    pub lag: IntGauge,
+    pub blocks_processed: IntCounter,
+    pub blocks_per_second: Gauge,
+    pub logs_per_block: Histogram,
+    pub transfers_per_block: Histogram,
+    pub rpc_latency: HistogramVec,
+    pub rpc_errors: IntCounterVec,
+    /// 1 while an RPC endpoint is used, 0 while it is skipped after repeated failures
+    pub rpc_endpoint_healthy: IntGaugeVec,
+    pub rpc_failovers: IntCounter,
+    pub rpc_quorum_failures: IntCounter,
+    pub rpc_retries: IntCounterVec,
+    pub rpc_throttled: IntCounterVec,
+    /// 1 while the RPC circuit breaker holds back requests
+    pub rpc_circuit_open: IntGauge,
+    pub reorgs: IntCounter,
+    pub reorg_depth: Histogram,
+    pub db_write_latency: HistogramVec,
+    /// 1 if the last verification passed, 0 if it failed, -1 before the first run
+    pub verification_result: IntGauge,
+    pub verification_timestamp: IntGauge,
+    pub verification_checked: IntCounter,
+    pub verification_mismatches: IntCounter,
+    /// Mismatches found by the last verification
+    pub verification_last_mismatches: IntGauge,
+    /// 1 once the tracker stopped indexing because of an unrecoverable error
+    pub halted: IntGauge,
+    pub last_block_timestamp: IntGauge,
+    recent_blocks: Mutex<VecDeque<Instant>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("brc20_tracker".to_string()), None).unwrap();
+
+        let indexed_height = IntGauge::new(
+            "indexed_height",
+            "Last block height written to the database",
+        )
+        .unwrap();
+        let tip_height =
+            IntGauge::new("tip_height", "Latest block height reported by the RPC node").unwrap();
+        let lag =
+            IntGauge::new("lag_blocks", "Blocks between the node tip and the database").unwrap();
+        let blocks_processed =
+            IntCounter::new("blocks_processed_total", "Blocks indexed since start").unwrap();
+        let blocks_per_second = Gauge::new(
+            "blocks_per_second",
+            "Blocks indexed per second over the last minute",
+        )
+        .unwrap();
+        let logs_per_block = Histogram::with_opts(
+            HistogramOpts::new("logs_per_block", "Logs fetched per block")
+                .buckets(vec![0.0, 1.0, 10.0, 100.0, 1000.0, 10000.0]),
+        )
+        .unwrap();
+        let transfers_per_block = Histogram::with_opts(
+            HistogramOpts::new("transfers_per_block", "Transfer events applied per block")
+                .buckets(vec![0.0, 1.0, 10.0, 100.0, 1000.0, 10000.0]),
+        )
+        .unwrap();
+        let rpc_latency = HistogramVec::new(
+            HistogramOpts::new("rpc_latency_seconds", "Latency of RPC calls to the node"),
+            &["method"],
+        )
+        .unwrap();
+        let rpc_errors = IntCounterVec::new(
+            Opts::new("rpc_errors_total", "Failed RPC calls to the node"),
+            &["method"],
+        )
+        .unwrap();
+        let rpc_endpoint_healthy = IntGaugeVec::new(
+            Opts::new(
+                "rpc_endpoint_healthy",
+                "1 if the RPC endpoint is in use, 0 while it is skipped after failures",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let rpc_failovers = IntCounter::new(
+            "rpc_failovers_total",
+            "RPC requests retried on the next endpoint",
+        )
+        .unwrap();
+        let rpc_quorum_failures = IntCounter::new(
+            "rpc_quorum_failures_total",
+            "Block requests where no majority of endpoints agreed on the hash",
+        )
+        .unwrap();
+        let rpc_retries = IntCounterVec::new(
+            Opts::new("rpc_retries_total", "RPC requests retried after a failure"),
+            &["method"],
+        )
+        .unwrap();
+        let rpc_throttled = IntCounterVec::new(
+            Opts::new(
+                "rpc_throttled_total",
+                "RPC requests held back by a rate or in-flight limit",
+            ),
+            &["limit"],
+        )
+        .unwrap();
+        let rpc_circuit_open = IntGauge::new(
+            "rpc_circuit_open",
+            "1 while the RPC circuit breaker holds back requests",
+        )
+        .unwrap();
+        let reorgs = IntCounter::new("reorgs_total", "Reorgs rolled back since start").unwrap();
+        let reorg_depth = Histogram::with_opts(
+            HistogramOpts::new(
+                "reorg_depth_blocks",
+                "Number of blocks rolled back per reorg",
+            )
+            .buckets(vec![1.0, 2.0, 3.0, 5.0, 10.0]),
+        )
+        .unwrap();
+        let db_write_latency = HistogramVec::new(
+            HistogramOpts::new("db_write_latency_seconds", "Latency of SQLite writes").buckets(
+                vec![
+                    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+                ],
+            ),
+            &["operation"],
+        )
+        .unwrap();
+        let verification_result = IntGauge::new(
+            "verification_last_result",
+            "Result of the last verification, 1 passed, 0 failed, -1 never ran",
+        )
+        .unwrap();
+        verification_result.set(-1);
+        let verification_timestamp = IntGauge::new(
+            "verification_last_timestamp_seconds",
+            "Unix time of the last finished verification",
+        )
+        .unwrap();
+        let verification_checked = IntCounter::new(
+            "verification_checked_total",
+            "Balances compared against the node by verifications",
+        )
+        .unwrap();
+        let verification_mismatches = IntCounter::new(
+            "verification_mismatches_total",
+            "Balances that differed from the node during verifications",
+        )
+        .unwrap();
+        let verification_last_mismatches = IntGauge::new(
+            "verification_last_mismatches",
+            "Balances that differed from the node during the last verification",
+        )
+        .unwrap();
+        let halted = IntGauge::new(
+            "halted",
+            "1 if indexing stopped because of an unrecoverable error",
+        )
+        .unwrap();
+        let last_block_timestamp = IntGauge::new(
+            "last_block_timestamp_seconds",
+            "Unix time the last block was indexed, or the start time before the first block",
+        )
+        .unwrap();
+        last_block_timestamp.set(unix_time());
+
+        registry.register(Box::new(indexed_height.clone())).unwrap();
+        registry.register(Box::new(tip_height.clone())).unwrap();
+        registry.register(Box::new(lag.clone())).unwrap();
+        registry
+            .register(Box::new(blocks_processed.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(blocks_per_second.clone()))
+            .unwrap();
+        registry.register(Box::new(logs_per_block.clone())).unwrap();
+        registry
+            .register(Box::new(transfers_per_block.clone()))
+            .unwrap();
+        registry.register(Box::new(rpc_latency.clone())).unwrap();
+        registry.register(Box::new(rpc_errors.clone())).unwrap();
+        registry
+            .register(Box::new(rpc_endpoint_healthy.clone()))
+            .unwrap();
+        registry.register(Box::new(rpc_failovers.clone())).unwrap();
+        registry
+            .register(Box::new(rpc_quorum_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(rpc_retries.clone())).unwrap();
+        registry.register(Box::new(rpc_throttled.clone())).unwrap();
+        registry
+            .register(Box::new(rpc_circuit_open.clone()))
+            .unwrap();
+        registry.register(Box::new(reorgs.clone())).unwrap();
+        registry.register(Box::new(reorg_depth.clone())).unwrap();
+        registry
+            .register(Box::new(db_write_latency.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(verification_result.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(verification_timestamp.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(verification_checked.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(verification_mismatches.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(verification_last_mismatches.clone()))
+            .unwrap();
+        registry.register(Box::new(halted.clone())).unwrap();
+        registry
+            .register(Box::new(last_block_timestamp.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            indexed_height,
+            tip_height,
+            lag,
+            blocks_processed,
+            blocks_per_second,
+            logs_per_block,
+            transfers_per_block,
+            rpc_latency,
+            rpc_errors,
+            rpc_endpoint_healthy,
+            rpc_failovers,
+            rpc_quorum_failures,
+            rpc_retries,
+            rpc_throttled,
+            rpc_circuit_open,
+            reorgs,
+            reorg_depth,
+            db_write_latency,
+            verification_result,
+            verification_timestamp,
+            verification_checked,
+            verification_mismatches,
+            verification_last_mismatches,
+            halted,
+            last_block_timestamp,
+            recent_blocks: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn set_indexed_height(&self, height: u64) {
+        self.indexed_height.set(height as i64);
+        self.update_lag();
+    }
+
+    pub fn set_tip_height(&self, height: u64) {
+        self.tip_height.set(height as i64);
+        self.update_lag();
+    }
+
+    fn update_lag(&self) {
+        self.lag
+            .set((self.tip_height.get() - self.indexed_height.get()).max(0));
+    }
+
+    pub fn block_processed(&self, logs: usize, transfers: usize) {
+        self.blocks_processed.inc();
+        self.logs_per_block.observe(logs as f64);
+        self.transfers_per_block.observe(transfers as f64);
+        self.last_block_timestamp.set(unix_time());
+
+        let now = Instant::now();
+        let mut recent_blocks = self.recent_blocks.lock().unwrap();
+        recent_blocks.push_back(now);
+        while recent_blocks
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > BLOCK_RATE_WINDOW)
+        {
+            recent_blocks.pop_front();
+        }
+        self.blocks_per_second
+            .set(recent_blocks.len() as f64 / BLOCK_RATE_WINDOW.as_secs_f64());
+    }
+
+    pub fn reorg(&self, depth: u64) {
+        self.reorgs.inc();
+        self.reorg_depth.observe(depth as f64);
+    }
+
+    pub fn verification_finished(&self, checked: u64, mismatches: usize) {
+        self.verification_result.set((mismatches == 0) as i64);
+        self.verification_timestamp.set(unix_time());
+        self.verification_checked.inc_by(checked);
+        self.verification_mismatches.inc_by(mismatches as u64);
+        self.verification_last_mismatches.set(mismatches as i64);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+pub fn unix_time() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// Runs an RPC call, recording its latency and whether it failed under `method`
+pub async fn observe_rpc<T, E>(
+    method: &str,
+    call: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let timer = METRICS
+        .rpc_latency
+        .with_label_values(&[method])
+        .start_timer();
+    let result = call.await;
+    timer.observe_duration();
+    if result.is_err() {
+        METRICS.rpc_errors.with_label_values(&[method]).inc();
+    }
+    result
+}
@@ -0,0 +1,256 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::metrics::METRICS;
+
+/// How failed RPC calls are retried, shared by every call the tracker makes
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts per call including the first one, 1 disables retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Fraction of the backoff that is randomized, so clients don't retry in lockstep
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `retry`, counting from 0. Doubles each time up to
+    /// `max_backoff`, then moves by up to `jitter` in either direction.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let jitter = 1.0 + self.jitter * (fastrand::f64() * 2.0 - 1.0);
+        backoff.mul_f64(jitter.max(0.0))
+    }
+
+    /// Runs `call` until it succeeds, `retryable` rejects its error or the attempts run out
+    pub async fn retry<T, E, F, Fut>(
+        &self,
+        method: &str,
+        retryable: impl Fn(&E) -> bool,
+        mut call: F,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            // Only the error is kept across the sleep, so `T` doesn't have to be `Send`
+            let err = match call().await {
+                Err(err) if attempt < self.max_attempts && retryable(&err) => err,
+                result => return result,
+            };
+            let delay = self.backoff(attempt - 1);
+            warn!(
+                method,
+                attempt,
+                max_attempts = self.max_attempts,
+                delay_ms = delay.as_millis() as u64,
+                error = %err,
+                "Retrying RPC request"
+            );
+            METRICS.rpc_retries.with_label_values(&[method]).inc();
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Stops sending requests after repeated failures, so a node that is down isn't hammered
+/// with retries. Once `cooldown` has passed a single request is let through, and the
+/// circuit closes again if it succeeds.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// A `threshold` of 0 never opens the circuit
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(CircuitState::default()),
+        }
+    }
+
+    /// Returns how long until the next request is allowed if the circuit is open
+    pub fn check(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => Ok(()),
+            Some(open_until) => {
+                let now = Instant::now();
+                if now < open_until {
+                    Err(open_until - now)
+                } else {
+                    // Let this request probe the node and hold back the others for another
+                    // cooldown, in case it never reports back
+                    state.open_until = Some(now + self.cooldown);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    pub fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        if success {
+            if state.open_until.is_some() {
+                info!("RPC circuit breaker closed");
+            }
+            *state = CircuitState::default();
+        } else {
+            state.consecutive_failures += 1;
+            if self.threshold > 0 && state.consecutive_failures >= self.threshold {
+                if state.open_until.is_none() {
+                    warn!(
+                        failures = state.consecutive_failures,
+                        cooldown_secs = self.cooldown.as_secs(),
+                        "RPC circuit breaker opened"
+                    );
+                }
+                state.open_until = Some(Instant::now() + self.cooldown);
+            }
+        }
+        METRICS
+            .rpc_circuit_open
+            .set(state.open_until.is_some() as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            jitter: 0.0,
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(3), Duration::from_millis(500));
+        assert_eq!(policy.backoff(40), Duration::from_millis(500));
+
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let backoff = policy.backoff(0);
+            assert!(backoff >= Duration::from_millis(50) && backoff <= Duration::from_millis(150));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            jitter: 0.0,
+        };
+        let mut calls = 0;
+        let result: Result<(), String> = policy
+            .retry(
+                "test",
+                |_| true,
+                || {
+                    calls += 1;
+                    async { Err("down".to_string()) }
+                },
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), String> = policy
+            .retry(
+                "test",
+                |_| false,
+                || {
+                    calls += 1;
+                    async { Err("bad request".to_string()) }
+                },
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result: Result<u32, String> = policy
+            .retry(
+                "test",
+                |_| true,
+                || {
+                    calls += 1;
+                    let calls = calls;
+                    async move {
+                        if calls < 2 {
+                            Err("down".to_string())
+                        } else {
+                            Ok(calls)
+                        }
+                    }
+                },
+            )
+            .await;
+        assert_eq!(result, Ok(2));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        assert!(breaker.check().is_ok());
+        breaker.record(false);
+        assert!(breaker.check().is_ok());
+        breaker.record(false);
+        assert!(breaker.check().unwrap_err() > Duration::from_secs(59));
+
+        // Once the cooldown has passed one probe goes through and the next waits for it
+        breaker.state.lock().unwrap().open_until = Some(Instant::now());
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+        breaker.record(true);
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record(false);
+        }
+        assert!(breaker.check().is_ok());
+    }
+}
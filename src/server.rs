@@ -0,0 +1,355 @@
+use std::error::Error;
+
+use jsonrpsee::{
+    core::{RpcResult, async_trait},
+    proc_macros::rpc,
+    server::{Server, ServerHandle},
+    types::{ErrorObjectOwned, error::INVALID_PARAMS_CODE},
+};
+use serde::Serialize;
+use tracing::info;
+
+use crate::database::{BalanceDatabase, BalanceSnapshot, HolderCursor, HoldersPage, Ticker};
+
+/// Upper bound for `getTopHolders` page sizes
+const MAX_HOLDERS_PAGE: u32 = 1000;
+
+/// Wraps every result with the height and block hash the database was at when it was read
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Indexed<T> {
+    pub indexed_height: u64,
+    pub block_hash: Option<String>,
+    pub result: T,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BalanceResponse {
+    pub wallet: String,
+    pub ticker: String,
+    /// Decimal string, amounts don't fit in a JSON number
+    pub amount: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TickerResponse {
+    pub ticker: String,
+    pub ticker_hash: String,
+    pub contract_address: String,
+}
+
+impl From<Ticker> for TickerResponse {
+    fn from(ticker: Ticker) -> Self {
+        TickerResponse {
+            ticker: ticker.ticker,
+            ticker_hash: ticker.ticker_hash,
+            contract_address: ticker.contract_address,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HolderResponse {
+    pub wallet: String,
+    pub amount: String,
+    /// Share of the total supply in percent
+    pub percentage: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HoldersResponse {
+    pub ticker: String,
+    pub total_supply: String,
+    pub holders: Vec<HolderResponse>,
+    /// Pass to the next call to continue after the last holder, `null` on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl HoldersResponse {
+    fn new(ticker: String, page: HoldersPage) -> Self {
+        HoldersResponse {
+            ticker,
+            total_supply: page.total_supply.to_string(),
+            holders: page
+                .holders
+                .iter()
+                .map(|holder| HolderResponse {
+                    percentage: page.percentage(holder.amount),
+                    wallet: holder.wallet.clone(),
+                    amount: holder.amount.to_string(),
+                })
+                .collect(),
+            next_cursor: page.next_cursor.map(|cursor| cursor.encode()),
+        }
+    }
+}
+
+fn invalid_params(message: String) -> ErrorObjectOwned {
+    ErrorObjectOwned::owned(INVALID_PARAMS_CODE, message, None::<()>)
+}
+
+#[rpc(server)]
+pub trait BalanceApi {
+    /// Returns the current balance of a wallet for a ticker, 0 if the wallet never held it
+    #[method(name = "getBalance")]
+    async fn get_balance(
+        &self,
+        wallet: String,
+        ticker: String,
+    ) -> RpcResult<Indexed<BalanceResponse>>;
+
+    /// Returns all current balances of a wallet
+    #[method(name = "getWalletBalances")]
+    async fn get_wallet_balances(&self, wallet: String)
+    -> RpcResult<Indexed<Vec<BalanceResponse>>>;
+
+    /// Returns the balance of a wallet for a ticker right after `height` was indexed
+    #[method(name = "getBalanceAt")]
+    async fn get_balance_at(
+        &self,
+        wallet: String,
+        ticker: String,
+        height: u64,
+    ) -> RpcResult<Indexed<BalanceResponse>>;
+
+    /// Returns all balances of a wallet right after `height` was indexed
+    #[method(name = "getWalletBalancesAt")]
+    async fn get_wallet_balances_at(
+        &self,
+        wallet: String,
+        height: u64,
+    ) -> RpcResult<Indexed<Vec<BalanceResponse>>>;
+
+    /// Returns all wallets holding a ticker right after `height` was indexed
+    #[method(name = "getTickerHoldersAt")]
+    async fn get_ticker_holders_at(
+        &self,
+        ticker: String,
+        height: u64,
+    ) -> RpcResult<Indexed<Vec<BalanceResponse>>>;
+
+    /// Returns holders of a ticker ranked by balance, at the current height unless `height` is
+    /// given, `limit` defaults to 100
+    #[method(name = "getTopHolders")]
+    async fn get_top_holders(
+        &self,
+        ticker: String,
+        limit: Option<u32>,
+        cursor: Option<String>,
+        height: Option<u64>,
+    ) -> RpcResult<Indexed<HoldersResponse>>;
+
+    /// Returns a ticker by name
+    #[method(name = "getTicker")]
+    async fn get_ticker(&self, ticker: String) -> RpcResult<Indexed<Option<TickerResponse>>>;
+
+    /// Returns all known tickers
+    #[method(name = "listTickers")]
+    async fn list_tickers(&self) -> RpcResult<Indexed<Vec<TickerResponse>>>;
+
+    /// Returns the last indexed block height
+    #[method(name = "getIndexedHeight")]
+    async fn get_indexed_height(&self) -> RpcResult<Indexed<u64>>;
+}
+
+pub struct BalanceApiImpl {
+    database: BalanceDatabase,
+}
+
+impl BalanceApiImpl {
+    /// Every call reads through one snapshot, so the result belongs to the reported height
+    async fn snapshot(&self) -> BalanceSnapshot {
+        self.database.snapshot(None).await.unwrap()
+    }
+
+    /// Rejects heights the database hasn't reached yet, they would silently return current data
+    fn check_height(snapshot: &BalanceSnapshot, height: u64) -> RpcResult<()> {
+        if height > snapshot.block_height {
+            return Err(invalid_params(format!(
+                "Height {} is not indexed yet, indexed height is {}",
+                height, snapshot.block_height
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn indexed<T>(snapshot: BalanceSnapshot, result: T) -> Indexed<T> {
+    Indexed {
+        indexed_height: snapshot.block_height,
+        block_hash: snapshot.block_hash,
+        result,
+    }
+}
+
+#[async_trait]
+impl BalanceApiServer for BalanceApiImpl {
+    async fn get_balance(
+        &self,
+        wallet: String,
+        ticker: String,
+    ) -> RpcResult<Indexed<BalanceResponse>> {
+        let wallet = wallet.to_lowercase();
+        let mut snapshot = self.snapshot().await;
+        let amount = snapshot
+            .get_balance(wallet.clone(), ticker.clone())
+            .await
+            .unwrap_or(0);
+        Ok(indexed(
+            snapshot,
+            BalanceResponse {
+                wallet,
+                ticker,
+                amount: amount.to_string(),
+            },
+        ))
+    }
+
+    async fn get_wallet_balances(
+        &self,
+        wallet: String,
+    ) -> RpcResult<Indexed<Vec<BalanceResponse>>> {
+        let wallet = wallet.to_lowercase();
+        let mut snapshot = self.snapshot().await;
+        let balances = snapshot
+            .get_wallet_balances(wallet.clone())
+            .await
+            .into_iter()
+            .map(|(ticker, amount)| BalanceResponse {
+                wallet: wallet.clone(),
+                ticker,
+                amount: amount.to_string(),
+            })
+            .collect();
+        Ok(indexed(snapshot, balances))
+    }
+
+    async fn get_balance_at(
+        &self,
+        wallet: String,
+        ticker: String,
+        height: u64,
+    ) -> RpcResult<Indexed<BalanceResponse>> {
+        let mut snapshot = self.snapshot().await;
+        Self::check_height(&snapshot, height)?;
+        let wallet = wallet.to_lowercase();
+        let amount = snapshot
+            .get_balance_at(wallet.clone(), ticker.clone(), height)
+            .await
+            .unwrap_or(0);
+        Ok(indexed(
+            snapshot,
+            BalanceResponse {
+                wallet,
+                ticker,
+                amount: amount.to_string(),
+            },
+        ))
+    }
+
+    async fn get_wallet_balances_at(
+        &self,
+        wallet: String,
+        height: u64,
+    ) -> RpcResult<Indexed<Vec<BalanceResponse>>> {
+        let mut snapshot = self.snapshot().await;
+        Self::check_height(&snapshot, height)?;
+        let wallet = wallet.to_lowercase();
+        let balances = snapshot
+            .get_wallet_balances_at(wallet.clone(), height)
+            .await
+            .into_iter()
+            .map(|(ticker, amount)| BalanceResponse {
+                wallet: wallet.clone(),
+                ticker,
+                amount: amount.to_string(),
+            })
+            .collect();
+        Ok(indexed(snapshot, balances))
+    }
+
+    async fn get_ticker_holders_at(
+        &self,
+        ticker: String,
+        height: u64,
+    ) -> RpcResult<Indexed<Vec<BalanceResponse>>> {
+        let mut snapshot = self.snapshot().await;
+        Self::check_height(&snapshot, height)?;
+        let holders = snapshot
+            .get_ticker_holders_at(ticker.clone(), height)
+            .await
+            .into_iter()
+            .map(|(wallet, amount)| BalanceResponse {
+                wallet,
+                ticker: ticker.clone(),
+                amount: amount.to_string(),
+            })
+            .collect();
+        Ok(indexed(snapshot, holders))
+    }
+
+    async fn get_top_holders(
+        &self,
+        ticker: String,
+        limit: Option<u32>,
+        cursor: Option<String>,
+        height: Option<u64>,
+    ) -> RpcResult<Indexed<HoldersResponse>> {
+        let mut snapshot = self.snapshot().await;
+        if let Some(height) = height {
+            Self::check_height(&snapshot, height)?;
+        }
+        let limit = limit.unwrap_or(100);
+        if limit == 0 || limit > MAX_HOLDERS_PAGE {
+            return Err(invalid_params(format!(
+                "Limit must be between 1 and {}",
+                MAX_HOLDERS_PAGE
+            )));
+        }
+        let cursor = match cursor {
+            Some(cursor) => Some(
+                HolderCursor::decode(&cursor)
+                    .ok_or_else(|| invalid_params(format!("Invalid cursor: {}", cursor)))?,
+            ),
+            None => None,
+        };
+        let page = snapshot
+            .get_top_holders(ticker.clone(), height, cursor.as_ref(), limit)
+            .await;
+        Ok(indexed(snapshot, HoldersResponse::new(ticker, page)))
+    }
+
+    async fn get_ticker(&self, ticker: String) -> RpcResult<Indexed<Option<TickerResponse>>> {
+        let mut snapshot = self.snapshot().await;
+        let ticker = snapshot.get_ticker(ticker).await.map(Into::into);
+        Ok(indexed(snapshot, ticker))
+    }
+
+    async fn list_tickers(&self) -> RpcResult<Indexed<Vec<TickerResponse>>> {
+        let mut snapshot = self.snapshot().await;
+        let tickers = snapshot
+            .list_tickers()
+            .await
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        Ok(indexed(snapshot, tickers))
+    }
+
+    async fn get_indexed_height(&self) -> RpcResult<Indexed<u64>> {
+        let snapshot = self.snapshot().await;
+        let height = snapshot.block_height;
+        Ok(indexed(snapshot, height))
+    }
+}
+
+/// Starts the query server in the background, it keeps running until the handle is stopped
+pub async fn start(addr: &str, database: BalanceDatabase) -> Result<ServerHandle, Box<dyn Error>> {
+    let server = Server::builder().build(addr).await?;
+    info!("Query server listening on {}", server.local_addr()?);
+    Ok(server.start(BalanceApiImpl { database }.into_rpc()))
+}
@@ -0,0 +1,130 @@
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Shutdown requests from SIGINT or SIGTERM, cheap to clone and check
+#[derive(Clone)]
+pub struct Shutdown {
+    signals: watch::Receiver<u32>,
+}
+
+impl Shutdown {
+    /// Starts listening for signals in the background
+    pub fn listen() -> Self {
+        let (sender, signals) = watch::channel(0);
+        // Registered before returning, so an early signal doesn't kill the process outright
+        let mut listener = SignalListener::new();
+        tokio::spawn(async move {
+            loop {
+                let signal = listener.recv().await;
+                let count = *sender.borrow() + 1;
+                if count == 1 {
+                    warn!(
+                        signal,
+                        "Shutdown requested, send it again to stop right away"
+                    );
+                } else {
+                    warn!(signal, "Shutdown requested again");
+                }
+                sender.send_replace(count);
+            }
+        });
+        Shutdown { signals }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.signals.borrow() > 0
+    }
+
+    /// Resolves once a shutdown is requested
+    pub async fn requested(&self) {
+        self.signals_received(1).await
+    }
+
+    /// Resolves once a second signal asks to stop without waiting
+    pub async fn forced(&self) {
+        self.signals_received(2).await
+    }
+
+    async fn signals_received(&self, count: u32) {
+        let mut signals = self.signals.clone();
+        let _ = signals.wait_for(|received| *received >= count).await;
+    }
+
+    #[cfg(test)]
+    fn manual() -> (watch::Sender<u32>, Self) {
+        let (sender, signals) = watch::channel(0);
+        (sender, Shutdown { signals })
+    }
+}
+
+#[cfg(unix)]
+struct SignalListener {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl SignalListener {
+    fn new() -> Self {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        SignalListener {
+            terminate: signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM"),
+            interrupt: signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT"),
+        }
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct SignalListener;
+
+#[cfg(not(unix))]
+impl SignalListener {
+    fn new() -> Self {
+        SignalListener
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C");
+        "Ctrl-C"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (sender, shutdown) = Shutdown::manual();
+        assert!(!shutdown.is_requested());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), shutdown.requested())
+                .await
+                .is_err()
+        );
+
+        sender.send_replace(1);
+        assert!(shutdown.clone().is_requested());
+        shutdown.requested().await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), shutdown.forced())
+                .await
+                .is_err()
+        );
+
+        sender.send_replace(2);
+        shutdown.forced().await;
+    }
+}
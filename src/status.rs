@@ -0,0 +1,162 @@
+use std::{error::Error, sync::Arc, time::Duration};
+
+use axum::{
+    Router,
+    extract::State,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::IntoResponse,
+    routing::get,
+};
+use serde_json::json;
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::info;
+
+use crate::{
+    database::BalanceDatabase,
+    metrics::{METRICS, observe_rpc, unix_time},
+    rpc::RpcPool,
+};
+
+/// How long `/readyz` waits for the node tip, probes expect a quick answer
+const READY_RPC_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Thresholds for `/readyz`
+#[derive(Debug, Clone)]
+pub struct ReadinessOptions {
+    /// Largest acceptable distance between the node tip and the indexed height
+    pub max_lag: u64,
+    /// How long the tracker may go without indexing a block while it is behind the tip
+    pub max_block_age: Duration,
+}
+
+impl Default for ReadinessOptions {
+    fn default() -> Self {
+        ReadinessOptions {
+            max_lag: 2,
+            max_block_age: Duration::from_secs(600),
+        }
+    }
+}
+
+struct StatusState {
+    database: BalanceDatabase,
+    client: RpcPool,
+    readiness: ReadinessOptions,
+}
+
+/// Starts the HTTP status server in the background
+pub async fn start(
+    addr: &str,
+    database: BalanceDatabase,
+    client: RpcPool,
+    readiness: ReadinessOptions,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Status server listening on {}", listener.local_addr()?);
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(Arc::new(StatusState {
+            database,
+            client,
+            readiness,
+        }));
+    Ok(tokio::spawn(async move {
+        axum::serve(listener, app)
+            .await
+            .expect("Status server failed");
+    }))
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.encode(),
+    )
+}
+
+/// The process is up and the database answers queries
+async fn healthz(State(state): State<Arc<StatusState>>) -> impl IntoResponse {
+    if state.database.is_reachable().await {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "database unreachable")
+    }
+}
+
+/// The tracker is close enough to the node tip to serve fresh data, and still making progress
+async fn readyz(State(state): State<Arc<StatusState>>) -> impl IntoResponse {
+    let mut reasons = Vec::new();
+
+    // Before the first block the database reports `first_block - 1`, which wraps at height 0
+    let indexed_height = Some(state.database.get_last_block().await)
+        .filter(|height| *height != state.database.first_block().wrapping_sub(1));
+    let tip_height = match observe_rpc(
+        "eth_blockNumber",
+        state
+            .client
+            .request_once::<String>("eth_blockNumber", READY_RPC_TIMEOUT),
+    )
+    .await
+    {
+        Ok(tip) => match u64::from_str_radix(tip.trim_start_matches("0x"), 16) {
+            Ok(tip) => {
+                METRICS.set_tip_height(tip);
+                Some(tip)
+            }
+            Err(_) => {
+                reasons.push(format!("invalid block number from node: {}", tip));
+                None
+            }
+        },
+        Err(err) => {
+            reasons.push(format!("node unreachable: {}", err));
+            None
+        }
+    };
+    let lag = tip_height.map(|tip| match indexed_height {
+        Some(indexed_height) => tip.saturating_sub(indexed_height),
+        None => (tip + 1).saturating_sub(state.database.first_block()),
+    });
+    let seconds_since_last_block = (unix_time() - METRICS.last_block_timestamp.get()).max(0);
+    let halted = METRICS.halted.get() == 1;
+
+    if halted {
+        reasons.push("indexing halted".to_string());
+    }
+    if let Some(lag) = lag {
+        if lag > state.readiness.max_lag {
+            reasons.push(format!(
+                "lag of {} blocks exceeds {}",
+                lag, state.readiness.max_lag
+            ));
+        }
+        if lag > 0 && seconds_since_last_block as u64 > state.readiness.max_block_age.as_secs() {
+            reasons.push(format!(
+                "no block indexed for {} seconds while behind the tip",
+                seconds_since_last_block
+            ));
+        }
+    }
+
+    let status = if reasons.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = json!({
+        "ready": reasons.is_empty(),
+        "indexed_height": indexed_height,
+        "tip_height": tip_height,
+        "lag": lag,
+        "halted": halted,
+        "seconds_since_last_block": seconds_since_last_block,
+        "reasons": reasons,
+    });
+    (
+        status,
+        [(CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+}
@@ -5,11 +5,21 @@ use alloy_sol_macro::sol;
 use alloy_sol_types::{SolCall, SolEvent};
 use brc20_prog::{
     Brc20ProgApiClient,
-    types::{EthCall, GetLogsFilter, RawBytes},
+    types::{BlockResponseED, EthCall, GetLogsFilter, LogED, RawBytes},
 };
-use jsonrpsee::http_client::HttpClient;
+use futures::StreamExt;
+use jsonrpsee::rpc_params;
+use serde_json::Value;
+use tracing::{Instrument, Span, debug, error, info, info_span, instrument, warn};
 
-use crate::database::BalanceDatabase;
+use crate::{
+    config::NetworkConfig,
+    database::{BalanceDatabase, BalanceRepair, DatabaseMetadata, SCHEMA_VERSION},
+    metrics::{METRICS, observe_rpc, unix_time},
+    rpc::{RpcPool, is_block_not_found},
+    shutdown::Shutdown,
+    verify::{Mismatch, VerificationReport, VerifyOptions},
+};
 
 sol! {
     /**
@@ -38,305 +48,930 @@ sol! {
     function name() public view virtual returns (string memory);
 }
 
-static CONTROLLER_ADDR: &str = "0xc54dd4581af2dbf18e4d90840226756e9d2b3cdb";
+/// How often the node tip is refreshed for the lag metrics
+const TIP_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// How long to wait before asking the node for a block it doesn't have yet
+const NEW_BLOCK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// Balances checked per batch of `eth_call`s during verification
+const VERIFY_CHUNK_SIZE: usize = 100;
+/// How often verification progress is logged
+const VERIFY_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+/// Background verification pauses while indexing is further behind the node than this, so it
+/// doesn't compete with catching up
+const BACKGROUND_VERIFY_MAX_LAG: i64 = 10;
 
+/// Outcome of `BalanceTracker::test`
 pub enum TestStatus {
-    Passed,
+    Finished(VerificationReport),
+    /// The node's tip wasn't at the verified height or moved during the run, nothing was compared
     NeedsRetry,
 }
 
+/// The chain diverged from the database further back than `check_reorg` looks
+#[derive(Debug)]
+pub struct ReorgTooDeep {
+    pub last_block: u64,
+}
+
+impl std::fmt::Display for ReorgTooDeep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Reorg too deep, no matching block hash within 10 blocks of {}",
+            self.last_block
+        )
+    }
+}
+
+impl Error for ReorgTooDeep {}
+
 pub struct BalanceTracker {
     database: BalanceDatabase,
-    client: HttpClient,
+    client: RpcPool,
+    network: NetworkConfig,
 }
 
 impl BalanceTracker {
-    pub fn new(database: BalanceDatabase, client: HttpClient) -> Self {
-        BalanceTracker { database, client }
+    pub fn new(database: BalanceDatabase, client: RpcPool, network: NetworkConfig) -> Self {
+        BalanceTracker {
+            database,
+            client,
+            network,
+        }
     }
 
-    pub async fn run(&self) {
-        self.database.init().await;
+    /// Indexes new blocks until `shutdown` is requested, which is only checked between
+    /// blocks. Dropping the future interrupts the current block, whose transaction is rolled
+    /// back.
+    pub async fn run(&self, shutdown: &Shutdown) {
         self.database.clear_residue().await;
+        // The metric otherwise only moves after the first indexed block or reorg
+        METRICS.set_indexed_height(self.database.get_last_block().await);
+        let mut last_tip_refresh: Option<std::time::Instant> = None;
+        // Failed iterations in a row, backs off the loop once the RPC retries are used up
+        let mut failures = 0;
         loop {
+            if shutdown.is_requested() {
+                info!(
+                    height = self.database.get_last_block().await,
+                    "Stopped indexing"
+                );
+                return;
+            }
+
+            if last_tip_refresh.is_none_or(|t| t.elapsed() > TIP_REFRESH_INTERVAL) {
+                if let Ok(tip) =
+                    observe_rpc("eth_blockNumber", self.client.eth_block_number()).await
+                    && let Ok(tip) = parse_hex_u64(&tip)
+                {
+                    METRICS.set_tip_height(tip);
+                }
+                last_tip_refresh = Some(std::time::Instant::now());
+            }
+
             match self.check_reorg().await {
                 Ok(_) => {}
+                Err(err) if err.is::<ReorgTooDeep>() => {
+                    // Keep the process alive so the halted state shows up in /readyz and
+                    // the database can be inspected or rolled back
+                    error!("{}, indexing halted", err);
+                    METRICS.halted.set(1);
+                    shutdown.requested().await;
+                    continue;
+                }
                 Err(err) => {
-                    eprintln!("Error checking for reorg: {}", err);
-                    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                    let delay = self.client.retry_policy().backoff(failures);
+                    failures += 1;
+                    warn!(
+                        attempt = failures,
+                        delay_ms = delay.as_millis() as u64,
+                        error = %err,
+                        "Error checking for reorg"
+                    );
+                    sleep_unless_shutdown(delay, shutdown).await;
                     continue;
                 }
             };
 
             let next_block = self.database.get_next_block().await;
-            println!("Processing block {}", next_block);
+            match self.index_block(next_block).await {
+                Ok(true) => failures = 0,
+                Ok(false) => {
+                    failures = 0;
+                    debug!("Waiting for new blocks...");
+                    sleep_unless_shutdown(NEW_BLOCK_POLL_INTERVAL, shutdown).await;
+                }
+                Err(err) => {
+                    let delay = self.client.retry_policy().backoff(failures);
+                    failures += 1;
+                    warn!(
+                        height = next_block,
+                        attempt = failures,
+                        delay_ms = delay.as_millis() as u64,
+                        error = %err,
+                        "Failed to index block, retrying..."
+                    );
+                    sleep_unless_shutdown(delay, shutdown).await;
+                }
+            }
+        }
+    }
 
-            let Ok(prog_block) = self
-                .client
-                .eth_get_block_by_number(next_block.to_string(), Some(false))
-                .await
-            else {
-                println!("Failed to fetch latest block, retrying...");
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                continue;
-            };
+    /// Applies the ticker creations and transfers of one block and records its hash. Returns
+    /// `false` without indexing anything if the node doesn't have the block yet.
+    #[instrument(name = "block", skip(self), fields(logs, transfers))]
+    async fn index_block(&self, next_block: u64) -> Result<bool, Box<dyn Error>> {
+        debug!("Processing block");
+
+        // The header and the logs come back in one round trip
+        let filter = GetLogsFilter {
+            from_block: Some(format!("0x{:x}", next_block)),
+            to_block: Some(format!("0x{:x}", next_block)),
+            address: None,
+            topics: None,
+        };
+        let responses = observe_rpc(
+            "batch",
+            self.client.batch::<Value>(vec![
+                (
+                    "eth_getBlockByNumber",
+                    rpc_params![next_block.to_string(), Some(false)],
+                ),
+                ("eth_getLogs", rpc_params![filter]),
+            ]),
+        )
+        .await;
+        let mut responses = match responses {
+            Ok(responses) => responses.into_iter(),
+            // The node answers with an error for blocks it hasn't produced yet
+            Err(err) if is_block_not_found(&err) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        let (Some(header), Some(logs)) = (responses.next(), responses.next()) else {
+            return Err("RPC batch returned fewer responses than requests".into());
+        };
+        let prog_block: BlockResponseED = serde_json::from_value(header)?;
+        if next_block > prog_block.number.into() {
+            return Ok(false);
+        }
+        let mut logs: Vec<LogED> = serde_json::from_value(logs)?;
+        // In quorum mode only the header is agreed on, the logs come from a single endpoint
+        // that may be on another fork
+        if let Some(log) = logs
+            .iter()
+            .find(|log| log.block_hash.bytes != prog_block.hash.bytes)
+        {
+            return Err(format!(
+                "Logs are from block {}, not from block {}",
+                log.block_hash.bytes, prog_block.hash.bytes
+            )
+            .into());
+        }
+
+        logs.sort_by(|a, b| {
+            a.transaction_index
+                .cmp(&b.transaction_index)
+                .then(a.log_index.cmp(&b.log_index))
+        });
 
-            if next_block > prog_block.number.into() {
-                println!("Waiting for new blocks...");
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        // Ticker names come from the node, fetch them before the block's writer transaction
+        // is opened so it isn't held across RPC calls
+        let mut ticker_names = std::collections::HashMap::new();
+        for log in &logs {
+            let address_string = log.address.address.to_string().to_lowercase();
+            if address_string == self.network.controller_address
+                && log.topics[0].bytes == BRC20Created::SIGNATURE_HASH
+            {
+                // Handle BRC20Created event, add ticker to database
+                let call = EthCall {
+                    from: Some(Address::ZERO.into()),
+                    to: Some(address_from_topic(log.topics[2].bytes).into()),
+                    data: Some(RawBytes::new(format!(
+                        "0x{}",
+                        hex::encode(nameCall::new(()).abi_encode())
+                    ))),
+                };
+                let ticker_name = nameCall::abi_decode_returns(
+                    hex::decode(
+                        observe_rpc("eth_call", self.client.eth_call(call, None))
+                            .await
+                            .map_err(|err| format!("Failed to call name function: {}", err))?
+                            .trim_start_matches("0x"),
+                    )
+                    .expect("Failed to decode hex")
+                    .as_slice(),
+                )
+                .unwrap();
+                ticker_names.insert(log.topics[2].bytes, ticker_name);
             }
+        }
 
-            let Ok(mut logs) = self
-                .client
-                .eth_get_logs(GetLogsFilter {
-                    from_block: Some(format!("0x{:x}", next_block)),
-                    to_block: Some(format!("0x{:x}", next_block)),
-                    address: None,
-                    topics: None,
-                })
-                .await
-            else {
-                println!("Failed to fetch logs, retrying...");
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                continue;
-            };
+        let log_count = logs.len();
+        let mut transfer_count = 0;
+        let mut block = self.database.begin_block(next_block).await;
+        for log in logs {
+            let address_string = log.address.address.to_string().to_lowercase();
+            let tx_hash = log.transaction_hash.bytes;
+            if address_string == self.network.controller_address {
+                if log.topics[0].bytes == BRC20Created::SIGNATURE_HASH {
+                    let ticker_name = &ticker_names[&log.topics[2].bytes];
+                    let contract_address = address_from_topic(log.topics[2].bytes)
+                        .to_string()
+                        .to_lowercase();
+                    info!(
+                        ticker = %ticker_name,
+                        contract_address = %contract_address,
+                        tx_hash = %tx_hash,
+                        "New ticker created"
+                    );
 
-            logs.sort_by(|a, b| {
-                a.transaction_index
-                    .cmp(&b.transaction_index)
-                    .then(a.log_index.cmp(&b.log_index))
-            });
+                    block
+                        .add_ticker(
+                            ticker_name,
+                            &log.topics[1].bytes.to_string(),
+                            &contract_address,
+                        )
+                        .await;
+                    continue;
+                }
+            } else {
+                if log.topics[0].bytes == Transfer::SIGNATURE_HASH {
+                    let Some(ticker_name) = block.get_ticker_by_address(&address_string).await
+                    else {
+                        continue;
+                    };
+                    let from_address = address_from_topic(log.topics[1].bytes)
+                        .to_string()
+                        .to_lowercase();
+                    let to_address = address_from_topic(log.topics[2].bytes)
+                        .to_string()
+                        .to_lowercase();
+                    let amount = amount_from_data(log.data.bytes);
+
+                    if amount == 0 {
+                        continue;
+                    }
+                    transfer_count += 1;
 
-            for log in logs {
-                let address_string = log.address.address.to_string().to_lowercase();
-                if address_string == CONTROLLER_ADDR {
-                    if log.topics[0].bytes == BRC20Created::SIGNATURE_HASH {
-                        // Handle BRC20Created event, add ticker to database
-                        let call = EthCall {
-                            from: Some(Address::ZERO.into()),
-                            to: Some(address_from_topic(log.topics[2].bytes).into()),
-                            data: Some(RawBytes::new(format!(
-                                "0x{}",
-                                hex::encode(nameCall::new(()).abi_encode())
-                            ))),
-                        };
-                        let ticker_name = nameCall::abi_decode_returns(
-                            hex::decode(
-                                self.client
-                                    .eth_call(call, None)
-                                    .await
-                                    .expect("Failed to call name function")
-                                    .trim_start_matches("0x"),
+                    if from_address == "0x0000000000000000000000000000000000000000" {
+                        // Handle transfer from zero address (minting)
+                        let balance = block
+                            .get_balance(&to_address, &ticker_name)
+                            .await
+                            .unwrap_or(0);
+                        debug!(
+                            ticker = %ticker_name,
+                            wallet = %to_address,
+                            amount = %amount,
+                            balance = %balance,
+                            tx_hash = %tx_hash,
+                            "Mint"
+                        );
+                        block
+                            .update_balance(
+                                &to_address,
+                                &ticker_name,
+                                balance.checked_add(amount).expect("Overflow"),
                             )
-                            .expect("Failed to decode hex")
-                            .as_slice(),
-                        )
-                        .unwrap();
+                            .await;
+                    } else if to_address == "0x0000000000000000000000000000000000000000" {
+                        // Handle transfer to zero address (burning)
+                        let balance = block
+                            .get_balance(&from_address, &ticker_name)
+                            .await
+                            .unwrap_or(0);
+                        debug!(
+                            ticker = %ticker_name,
+                            wallet = %from_address,
+                            amount = %amount,
+                            balance = %balance,
+                            tx_hash = %tx_hash,
+                            "Burn"
+                        );
+                        block
+                            .update_balance(
+                                &from_address,
+                                &ticker_name,
+                                balance.checked_sub(amount).expect("Insufficient balance"),
+                            )
+                            .await;
+                    } else {
+                        let from_balance = block
+                            .get_balance(&from_address, &ticker_name)
+                            .await
+                            .unwrap_or(0);
 
-                        println!(
-                            "New ticker created: {} at address {}",
-                            ticker_name,
-                            address_from_topic(log.topics[2].bytes)
+                        let to_balance = block
+                            .get_balance(&to_address, &ticker_name)
+                            .await
+                            .unwrap_or(0);
+
+                        debug!(
+                            ticker = %ticker_name,
+                            from = %from_address,
+                            to = %to_address,
+                            amount = %amount,
+                            from_balance = %from_balance,
+                            to_balance = %to_balance,
+                            tx_hash = %tx_hash,
+                            "Transfer"
                         );
 
-                        self.database
-                            .add_ticker(
-                                ticker_name,
-                                log.topics[1].bytes.to_string(),
-                                address_from_topic(log.topics[2].bytes)
-                                    .to_string()
-                                    .to_lowercase(),
+                        block
+                            .update_balance(
+                                &from_address,
+                                &ticker_name,
+                                from_balance
+                                    .checked_sub(amount)
+                                    .expect("Insufficient balance"),
+                            )
+                            .await;
+
+                        block
+                            .update_balance(
+                                &to_address,
+                                &ticker_name,
+                                to_balance.checked_add(amount).expect("Overflow"),
                             )
                             .await;
-                        continue;
-                    }
-                } else {
-                    if log.topics[0].bytes == Transfer::SIGNATURE_HASH {
-                        let Some(ticker_name) =
-                            self.database.get_ticker_by_address(address_string).await
-                        else {
-                            continue;
-                        };
-                        let from_address = address_from_topic(log.topics[1].bytes)
-                            .to_string()
-                            .to_lowercase();
-                        let to_address = address_from_topic(log.topics[2].bytes)
-                            .to_string()
-                            .to_lowercase();
-                        let amount = amount_from_data(log.data.bytes);
-
-                        if amount == 0 {
-                            continue;
-                        }
-
-                        if from_address == "0x0000000000000000000000000000000000000000" {
-                            // Handle transfer from zero address (minting)
-                            println!("Mint of {} ${} to {}", amount, ticker_name, to_address);
-                            let balance = self
-                                .database
-                                .get_balance(to_address.clone(), ticker_name.clone())
-                                .await
-                                .unwrap_or(0);
-                            self.database
-                                .update_balance(
-                                    next_block,
-                                    to_address,
-                                    ticker_name,
-                                    balance.checked_add(amount).expect("Overflow"),
-                                )
-                                .await;
-                        } else if to_address == "0x0000000000000000000000000000000000000000" {
-                            // Handle transfer to zero address (burning)
-                            println!("Burn of {} ${} from {}", amount, ticker_name, from_address);
-                            let balance = self
-                                .database
-                                .get_balance(from_address.clone(), ticker_name.clone())
-                                .await
-                                .unwrap_or(0);
-                            self.database
-                                .update_balance(
-                                    next_block,
-                                    from_address,
-                                    ticker_name,
-                                    balance.checked_sub(amount).expect("Insufficient balance"),
-                                )
-                                .await;
-                        } else {
-                            println!(
-                                "Transfer of {} ${} from {} to {}",
-                                amount, ticker_name, from_address, to_address
-                            );
-
-                            println!("Transaction hash: {:?}", log.transaction_hash);
-
-                            let from_balance = self
-                                .database
-                                .get_balance(from_address.clone(), ticker_name.clone())
-                                .await
-                                .unwrap_or(0);
-
-                            let to_balance = self
-                                .database
-                                .get_balance(to_address.clone(), ticker_name.clone())
-                                .await
-                                .unwrap_or(0);
-
-                            println!("From balance: {:?}", from_balance);
-                            println!("To balance: {:?}", to_balance);
-
-                            self.database
-                                .update_balance(
-                                    next_block,
-                                    from_address,
-                                    ticker_name.clone(),
-                                    from_balance
-                                        .checked_sub(amount)
-                                        .expect("Insufficient balance"),
-                                )
-                                .await;
-
-                            self.database
-                                .update_balance(
-                                    next_block,
-                                    to_address,
-                                    ticker_name.clone(),
-                                    to_balance.checked_add(amount).expect("Overflow"),
-                                )
-                                .await;
-                        }
                     }
                 }
             }
+        }
 
-            self.database
-                .set_block_hash(next_block, prog_block.hash.bytes.to_string())
-                .await;
+        block.commit(&prog_block.hash.bytes.to_string()).await;
+        METRICS.set_indexed_height(next_block);
+        METRICS.block_processed(log_count, transfer_count);
+
+        let span = Span::current();
+        span.record("logs", log_count);
+        span.record("transfers", transfer_count);
+        info!(hash = %prog_block.hash.bytes, "Indexed block");
+        Ok(true)
+    }
+
+    /// Makes sure the database was built for the configured network and the connected node.
+    ///
+    /// Only reads, refuses to run if the metadata recorded by `record_metadata` disagrees
+    /// with the configuration, or the node's chain id or block at `first_block` differ.
+    pub async fn check_consistency(&self) -> Result<(), Box<dyn Error>> {
+        let chain_id =
+            parse_hex_u64(&observe_rpc("eth_chainId", self.client.eth_chain_id()).await?)?;
+        if chain_id != self.network.chain_id {
+            return Err(format!(
+                "RPC node chain id 0x{:x} does not match network {} (expected 0x{:x})",
+                chain_id, self.network.name, self.network.chain_id
+            )
+            .into());
+        }
+
+        let expected = DatabaseMetadata {
+            network: self.network.name.clone(),
+            first_block: self.database.first_block(),
+            controller_address: self.network.controller_address.clone(),
+            chain_id,
+            schema_version: SCHEMA_VERSION,
+        };
+
+        if let Some(stored) = self.database.get_metadata().await {
+            let mut mismatches = Vec::new();
+            if stored.network != expected.network {
+                mismatches.push(format!(
+                    "network: database {}, configured {}",
+                    stored.network, expected.network
+                ));
+            }
+            if stored.first_block != expected.first_block {
+                mismatches.push(format!(
+                    "first block: database {}, configured {}",
+                    stored.first_block, expected.first_block
+                ));
+            }
+            if stored.controller_address != expected.controller_address {
+                mismatches.push(format!(
+                    "controller address: database {}, configured {}",
+                    stored.controller_address, expected.controller_address
+                ));
+            }
+            if stored.chain_id != expected.chain_id {
+                mismatches.push(format!(
+                    "chain id: database 0x{:x}, RPC node 0x{:x}",
+                    stored.chain_id, expected.chain_id
+                ));
+            }
+            if stored.schema_version > expected.schema_version {
+                mismatches.push(format!(
+                    "schema version: database {}, supported {}",
+                    stored.schema_version, expected.schema_version
+                ));
+            }
+            if !mismatches.is_empty() {
+                return Err(format!(
+                    "Database does not match the current configuration:\n  {}",
+                    mismatches.join("\n  ")
+                )
+                .into());
+            }
+        }
+
+        let first_block = self.database.first_block();
+        if let Some(stored_hash) = self.database.get_block_hash(first_block).await {
+            let prog_block = observe_rpc(
+                "eth_getBlockByNumber",
+                self.client
+                    .eth_get_block_by_number(first_block.to_string(), Some(false)),
+            )
+            .await?;
+            if prog_block.hash.bytes.to_string() != stored_hash {
+                return Err(format!(
+                    "Block {} hash mismatch: database {}, RPC node {}",
+                    first_block, stored_hash, prog_block.hash.bytes
+                )
+                .into());
+            }
         }
+
+        Ok(())
     }
 
-    /// Returns the last confirmed block
+    /// Writes the metadata on first start, after `check_consistency` confirmed the node's
+    /// chain id and while holding the writer lease
+    pub async fn record_metadata(&self) {
+        if self.database.get_metadata().await.is_some() {
+            return;
+        }
+        let metadata = DatabaseMetadata {
+            network: self.network.name.clone(),
+            first_block: self.database.first_block(),
+            controller_address: self.network.controller_address.clone(),
+            chain_id: self.network.chain_id,
+            schema_version: SCHEMA_VERSION,
+        };
+        info!(
+            network = %metadata.network,
+            first_block = metadata.first_block,
+            chain_id = format_args!("0x{:x}", metadata.chain_id),
+            "Recording database metadata"
+        );
+        self.database.set_metadata(&metadata).await;
+    }
+
+    /// Rolls back to the last block whose hash still matches the node, within 10 blocks
     pub async fn check_reorg(&self) -> Result<(), Box<dyn Error>> {
         let last_block = self.database.get_last_block().await;
-        for i in 0..10 {
-            let block_number = last_block - i;
+        let prog_block = observe_rpc(
+            "eth_getBlockByNumber",
+            self.client
+                .eth_get_block_by_number(last_block.to_string(), Some(false)),
+        )
+        .await?;
+        if self
+            .database
+            .validate_block_hash(last_block, prog_block.hash.bytes.to_string())
+            .await
+        {
+            return Ok(());
+        }
 
-            let prog_block = self
-                .client
-                .eth_get_block_by_number(block_number.to_string(), Some(false))
-                .await?;
+        // The tip changed, fetch the blocks below it in one batch to find where it forked
+        let block_numbers: Vec<u64> = (1..10).filter_map(|i| last_block.checked_sub(i)).collect();
+        let prog_blocks: Vec<BlockResponseED> = observe_rpc(
+            "eth_getBlockByNumber",
+            self.client.batch(
+                block_numbers
+                    .iter()
+                    .map(|block_number| {
+                        (
+                            "eth_getBlockByNumber",
+                            rpc_params![block_number.to_string(), Some(false)],
+                        )
+                    })
+                    .collect(),
+            ),
+        )
+        .await?;
 
+        for (block_number, prog_block) in block_numbers.into_iter().zip(prog_blocks) {
+            let i = last_block - block_number;
             if self
                 .database
                 .validate_block_hash(block_number, prog_block.hash.bytes.to_string())
                 .await
             {
-                if i != 0 {
-                    println!("Reorg detected!! Rolling back to block {}", block_number);
-                    self.database.reorg(block_number).await;
-                    println!("Rollback complete");
+                async {
+                    warn!("Reorg detected, rolling back");
+                    self.database.reorg(block_number + 1).await;
+                    METRICS.reorg(i);
+                    METRICS.set_indexed_height(block_number);
+                    info!("Rollback complete");
                 }
+                .instrument(info_span!(
+                    "reorg",
+                    from = last_block,
+                    to = block_number,
+                    depth = i
+                ))
+                .await;
                 return Ok(());
             }
         }
-        panic!("Reorg too deep, cannot recover");
+        Err(Box::new(ReorgTooDeep { last_block }))
     }
 
-    pub async fn test(&self) -> Result<TestStatus, Box<dyn Error>> {
-        let current_block = self.client.eth_block_number().await?;
-        let mut count = 1;
-        let total = 1000;
-        let pairs = self.database.random_wallet_ticker_pairs(total).await;
-        let controller_address: Address = CONTROLLER_ADDR.parse().unwrap();
-        for (wallet, ticker, amount) in pairs {
-            if count % (total / 10) == 0 {
-                println!("Testing {}/{}", count, total);
+    /// Compares the balances selected by `options` against the node and reports every
+    /// mismatch. brc20-prog answers `eth_call` with its latest state whatever block is asked
+    /// for, so balances are only compared while the node's tip is at the verified height.
+    /// Returns `NeedsRetry` if it isn't, or if a block arrives before the run is over. Past
+    /// heights fail unless the node is shown to answer for them, see `check_calls_pinned`.
+    pub async fn test(&self, options: &VerifyOptions) -> Result<TestStatus, Box<dyn Error>> {
+        let started = std::time::Instant::now();
+        let mut snapshot = self.database.snapshot(options.height).await?;
+        let mut report = VerificationReport {
+            block_height: snapshot.block_height,
+            block_hash: snapshot.block_hash.clone(),
+            ..Default::default()
+        };
+        let Some(block_hash) = report.block_hash.clone() else {
+            info!(
+                block_height = report.block_height,
+                "Block is not indexed, skipping verification"
+            );
+            return Ok(TestStatus::Finished(report));
+        };
+        let block_height = report.block_height;
+
+        let block_tag = format!("0x{:x}", block_height);
+        let prog_block = observe_rpc(
+            "eth_getBlockByNumber",
+            self.client
+                .eth_get_block_by_number(block_tag.clone(), Some(false)),
+        )
+        .await?;
+        if prog_block.hash.bytes.to_string() != block_hash {
+            return Err(format!(
+                "Block {} hash mismatch: database {}, RPC node {}, wait for the tracker to handle the reorg",
+                block_height, block_hash, prog_block.hash.bytes
+            )
+            .into());
+        }
+        let tip = self.node_tip().await?;
+        // Whether the node was shown to answer for `block_height` rather than from its tip
+        let pinned = if tip == block_height {
+            false
+        } else if options.height.is_some() && block_height < tip {
+            match self.database.changed_balance(block_height).await {
+                Some((wallet, ticker)) => {
+                    self.check_calls_pinned(wallet, ticker, &block_tag, tip)
+                        .await?;
+                    true
+                }
+                // Nothing changed since, the state at the tip is the state at `block_height`
+                // as long as the tip is the indexed block
+                None if tip == self.database.get_last_block().await => false,
+                None => {
+                    info!(
+                        block_height,
+                        tip, "Node tip is not at the indexed height, verification needs a retry"
+                    );
+                    return Ok(TestStatus::NeedsRetry);
+                }
             }
-            let ticker_bytes = ticker.clone().into_bytes();
-            let call = EthCall {
-                from: Some(Address::ZERO.into()),
-                to: Some(controller_address.into()),
-                data: Some(RawBytes::new(format!(
-                    "0x{}",
-                    hex::encode(
-                        balanceOfCall::new((Bytes::from(ticker_bytes), wallet.parse().unwrap()))
-                            .abi_encode()
-                    )
-                ))),
-            };
-            let balance = self.client.eth_call(call, None).await?;
-            let module_balance = amount_from_data(
-                hex::decode(balance.trim_start_matches("0x"))
-                    .unwrap()
-                    .into(),
+        } else {
+            info!(
+                block_height,
+                tip, "Node tip is not at the verified height, verification needs a retry"
             );
-            if module_balance != amount {
-                println!(
-                    "Mismatch for wallet {} ticker {}: db {} on-chain {}",
-                    wallet, ticker, amount, module_balance
+            return Ok(TestStatus::NeedsRetry);
+        };
+
+        let mut total = snapshot
+            .count_balances(&options.tickers, &options.wallets)
+            .await;
+        let sample = match options.sample {
+            Some(sample) => {
+                total = total.min(sample as u64);
+                Some(
+                    snapshot
+                        .random_balances(sample, &options.tickers, &options.wallets)
+                        .await,
+                )
+            }
+            None => None,
+        };
+        // Checking against the node takes a while, an open read transaction meanwhile would
+        // keep SQLite from checkpointing the WAL
+        drop(snapshot);
+        let chunks = match sample {
+            Some(sample) => futures::stream::iter(
+                sample
+                    .chunks(VERIFY_CHUNK_SIZE)
+                    .map(<[_]>::to_vec)
+                    .collect::<Vec<_>>(),
+            )
+            .boxed(),
+            None => self.balance_pages(block_height, options).boxed(),
+        };
+        info!(block_height, block_hash = %block_hash, total, "Verifying balances");
+
+        let mut results = chunks
+            .map(|chunk| self.check_balances(chunk, &block_tag))
+            .buffered(options.concurrency.max(1));
+        let mut last_progress = std::time::Instant::now();
+        while let Some(checked) = results.next().await {
+            for (wallet, ticker, amount, node) in checked? {
+                *report.tickers.entry(ticker.clone()).or_insert(0) += 1;
+                if node == amount {
+                    continue;
+                }
+                warn!(
+                    wallet = %wallet,
+                    ticker = %ticker,
+                    database = %amount,
+                    on_chain = %node,
+                    block_height,
+                    "Balance mismatch"
                 );
-                let mut next_block = self.client.eth_block_number().await?;
-                let mut indexed_block = self.database.get_last_block().await;
-                if next_block == current_block {
-                    return Err("Balance mismatch".into());
+                report.mismatches.push(Mismatch {
+                    wallet,
+                    ticker,
+                    database: amount,
+                    node,
+                    healed: false,
+                });
+            }
+            if last_progress.elapsed() >= VERIFY_PROGRESS_INTERVAL {
+                info!(
+                    checked = report.checked(),
+                    total,
+                    mismatches = report.mismatches.len(),
+                    "Verification progress"
+                );
+                last_progress = std::time::Instant::now();
+            }
+        }
+
+        if !pinned && self.node_tip().await? != tip {
+            info!(
+                block_height,
+                tip, "Node received a block during verification, verification needs a retry"
+            );
+            return Ok(TestStatus::NeedsRetry);
+        }
+
+        report.duration = started.elapsed();
+        METRICS.verification_finished(report.checked(), report.mismatches.len());
+        info!(
+            checked = report.checked(),
+            mismatches = report.mismatches.len(),
+            duration_secs = report.duration.as_secs(),
+            "Verification finished"
+        );
+        Ok(TestStatus::Finished(report))
+    }
+
+    /// Pages of `VERIFY_CHUNK_SIZE` balances at `block_height`, each read when it's needed
+    fn balance_pages<'a>(
+        &'a self,
+        block_height: u64,
+        options: &'a VerifyOptions,
+    ) -> impl futures::Stream<Item = Vec<(String, String, u128)>> + 'a {
+        // `None` once the last page was read, otherwise the key to continue after
+        futures::stream::unfold(Some(None), move |after| async move {
+            let page = self
+                .database
+                .balances_page(
+                    block_height,
+                    &options.tickers,
+                    &options.wallets,
+                    after?,
+                    VERIFY_CHUNK_SIZE as u32,
+                )
+                .await;
+            let next = (page.len() == VERIFY_CHUNK_SIZE).then(|| {
+                let (wallet, ticker, _) = page.last().unwrap();
+                Some((wallet.clone(), ticker.clone()))
+            });
+            (!page.is_empty()).then_some((page, next))
+        })
+    }
+
+    /// Verifies a sample of balances every `interval` until `shutdown` is requested. Meant to
+    /// run next to `run`: it reads through the read-only pool at whatever height is indexed at
+    /// the time, and only logs and counts mismatches. Runs during which the node moves past
+    /// that height are skipped. Blocks are committed whole, so the snapshot never includes part
+    /// of the block being indexed.
+    pub async fn verify_in_background(
+        &self,
+        interval: std::time::Duration,
+        options: &VerifyOptions,
+        shutdown: &Shutdown,
+    ) {
+        loop {
+            sleep_unless_shutdown(interval, shutdown).await;
+            if shutdown.is_requested() {
+                return;
+            }
+            // The lag gauge reads 0 until the tip was fetched once, so work it out here
+            let tip = METRICS.tip_height.get();
+            if tip == 0 {
+                debug!("Node tip not known yet, skipping background verification");
+                continue;
+            }
+            let lag = tip - self.database.get_last_block().await as i64;
+            if lag > BACKGROUND_VERIFY_MAX_LAG {
+                debug!(lag, "Indexing is behind, skipping background verification");
+                continue;
+            }
+            let report = tokio::select! {
+                report = self.test(options) => report,
+                _ = shutdown.requested() => return,
+            };
+            match report {
+                Ok(TestStatus::Finished(report)) if !report.passed() => error!(
+                    block_height = report.block_height,
+                    checked = report.checked(),
+                    mismatches = report.mismatches.len(),
+                    "Background verification found mismatching balances"
+                ),
+                Ok(TestStatus::Finished(_)) => {}
+                Ok(TestStatus::NeedsRetry) => {
+                    debug!("Node tip moved during background verification, skipping it")
                 }
-                println!("Received new block during the test, waiting for database to catch up...");
-                while u64::from_str_radix(&next_block.trim_start_matches("0x"), 16).unwrap()
-                    != indexed_block
-                {
-                    println!(
-                        "Waiting for database to catch up... current {}, indexed {}",
-                        next_block, indexed_block
+                Err(err) => warn!(error = %err, "Background verification failed"),
+            }
+        }
+    }
+
+    /// Reads the mismatching balances of `report` from the node again and overwrites the
+    /// database with them, recording every repair in `brc20_prog_balance_repairs`. Only
+    /// reports at the indexed height can be healed, while the node's tip is at that height
+    /// before and after the reads, and the caller has to hold the writer lease.
+    pub async fn heal(&self, report: &mut VerificationReport) -> Result<(), Box<dyn Error>> {
+        if report.mismatches.is_empty() {
+            return Ok(());
+        }
+        let indexed_height = self.database.get_last_block().await;
+        if report.block_height != indexed_height {
+            return Err(format!(
+                "Can only heal balances at the indexed height {}, not at {}",
+                indexed_height, report.block_height
+            )
+            .into());
+        }
+        let block_height = report.block_height;
+        let block_hash = report.block_hash.clone().unwrap_or_default();
+        let block_tag = format!("0x{:x}", block_height);
+        self.check_heal_tip(block_height).await?;
+        for mismatches in report.mismatches.chunks_mut(VERIFY_CHUNK_SIZE) {
+            let balances = mismatches
+                .iter()
+                .map(|m| (m.wallet.clone(), m.ticker.clone(), m.database))
+                .collect();
+            let checked = self.check_balances(balances, &block_tag).await?;
+            // The reads are the state at the tip, which has to still be the indexed block
+            self.check_heal_tip(block_height).await?;
+            for (mismatch, (wallet, ticker, database, node)) in mismatches.iter_mut().zip(checked) {
+                if node == database {
+                    warn!(
+                        wallet = %wallet,
+                        ticker = %ticker,
+                        block_height,
+                        "Balance matches the node on a second read, leaving it"
                     );
-                    next_block = self.client.eth_block_number().await?;
-                    indexed_block = self.database.get_last_block().await;
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    continue;
                 }
-                return Ok(TestStatus::NeedsRetry);
+                let repaired = self
+                    .database
+                    .repair_balance(&BalanceRepair {
+                        block_height,
+                        block_hash: block_hash.clone(),
+                        wallet: wallet.clone(),
+                        ticker: ticker.clone(),
+                        database_amount: database,
+                        node_amount: node,
+                        repaired_at: unix_time(),
+                    })
+                    .await;
+                if !repaired {
+                    warn!(
+                        wallet = %wallet,
+                        ticker = %ticker,
+                        block_height,
+                        "Balance changed since it was verified, leaving it"
+                    );
+                    continue;
+                }
+                warn!(
+                    wallet = %wallet,
+                    ticker = %ticker,
+                    database = %database,
+                    on_chain = %node,
+                    block_height,
+                    "Healed balance"
+                );
+                mismatch.node = node;
+                mismatch.healed = true;
             }
-            count += 1;
         }
-        Ok(TestStatus::Passed)
+        Ok(())
     }
+
+    /// Fails unless the node's tip is `block_height`. brc20-prog answers `eth_call` with its
+    /// latest state, so balances read from it only belong to `block_height` while it is the tip.
+    async fn check_heal_tip(&self, block_height: u64) -> Result<(), Box<dyn Error>> {
+        let tip = self.node_tip().await?;
+        if tip != block_height {
+            return Err(format!(
+                "Node tip is at block {}, not at the indexed height {}, refusing to heal from its balances",
+                tip, block_height
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Fails unless the node answers `eth_call` for `block_tag` rather than from its latest
+    /// state, told apart by a balance that changed since
+    async fn check_calls_pinned(
+        &self,
+        wallet: String,
+        ticker: String,
+        block_tag: &str,
+        tip: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let balance = vec![(wallet, ticker, 0)];
+        let pinned = self.check_balances(balance.clone(), block_tag).await?;
+        let latest = self.check_balances(balance, "latest").await?;
+        if pinned[0].3 == latest[0].3 {
+            return Err(format!(
+                "The node at block {} answers eth_call for block {} from its latest state, balances can't be verified at past heights",
+                tip,
+                parse_hex_u64(block_tag)?
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The height of the node's latest block
+    async fn node_tip(&self) -> Result<u64, Box<dyn Error>> {
+        let tip = observe_rpc("eth_blockNumber", self.client.eth_block_number()).await?;
+        Ok(parse_hex_u64(&tip)?)
+    }
+
+    /// Fetches the balances of `chunk` at `block_tag`, in the order they were given
+    async fn check_balances(
+        &self,
+        chunk: Vec<(String, String, u128)>,
+        block_tag: &str,
+    ) -> Result<Vec<(String, String, u128, u128)>, Box<dyn Error>> {
+        let controller_address: Address = self.network.controller_address.parse().unwrap();
+        let calls = chunk
+            .iter()
+            .map(|(wallet, ticker, _)| {
+                let ticker_bytes = ticker.clone().into_bytes();
+                let call = EthCall {
+                    from: Some(Address::ZERO.into()),
+                    to: Some(controller_address.into()),
+                    data: Some(RawBytes::new(format!(
+                        "0x{}",
+                        hex::encode(
+                            balanceOfCall::new((
+                                Bytes::from(ticker_bytes),
+                                wallet.parse().unwrap()
+                            ))
+                            .abi_encode()
+                        )
+                    ))),
+                };
+                ("eth_call", rpc_params![call, Some(block_tag)])
+            })
+            .collect();
+        let balances: Vec<String> = observe_rpc("eth_call", self.client.batch(calls)).await?;
+        Ok(chunk
+            .into_iter()
+            .zip(balances)
+            .map(|((wallet, ticker, amount), balance)| {
+                let node = amount_from_data(
+                    hex::decode(balance.trim_start_matches("0x"))
+                        .unwrap()
+                        .into(),
+                );
+                (wallet, ticker, amount, node)
+            })
+            .collect())
+    }
+}
+
+async fn sleep_unless_shutdown(duration: std::time::Duration, shutdown: &Shutdown) {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        _ = shutdown.requested() => {}
+    }
+}
+
+fn parse_hex_u64(value: &str) -> Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16)
 }
 
 fn address_from_topic(bytes: FixedBytes<32>) -> Address {
@@ -346,6 +981,108 @@ fn address_from_topic(bytes: FixedBytes<32>) -> Address {
 fn amount_from_data(bytes: Bytes) -> u128 {
     let mut arr = [0u8; 16];
     arr.copy_from_slice(&bytes.to_vec()[16..32]);
-    let amount = u128::from_be_bytes(arr);
-    amount
+    u128::from_be_bytes(arr)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonrpsee::{RpcModule, server::Server, types::ErrorObjectOwned};
+
+    use super::*;
+    use crate::{config::NetworkSection, database::tests::TestDatabase, rpc::RpcOptions};
+
+    /// Starts a node at the tip, it has no block 1 yet and answers like brc20-prog
+    async fn start_node() -> (String, jsonrpsee::server::ServerHandle) {
+        let mut module = RpcModule::new(());
+        module
+            .register_method("eth_getBlockByNumber", |_, _, _| {
+                Err::<Value, _>(ErrorObjectOwned::owned(
+                    400,
+                    "Block not found",
+                    None::<String>,
+                ))
+            })
+            .unwrap();
+        module
+            .register_method("eth_getLogs", |_, _, _| {
+                Ok::<_, ErrorObjectOwned>(Vec::<Value>::new())
+            })
+            .unwrap();
+        module
+            .register_method("eth_blockNumber", |_, _, _| {
+                Ok::<_, ErrorObjectOwned>("0x0".to_string())
+            })
+            .unwrap();
+        // brc20-prog ignores the block of `eth_call`, every balance is 5 at every block
+        module
+            .register_method("eth_call", |_, _, _| {
+                Ok::<_, ErrorObjectOwned>(format!("0x{:064x}", 5))
+            })
+            .unwrap();
+        let server = Server::builder().build("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", server.local_addr().unwrap());
+        (url, server.start(module))
+    }
+
+    #[tokio::test]
+    async fn test_index_unmined_block() {
+        let db = TestDatabase::new().await;
+        let (url, _server) = start_node().await;
+        let client = RpcPool::new(&[url], &RpcOptions::default(), false).unwrap();
+        let network =
+            NetworkConfig::resolve("mainnet", &NetworkSection::default(), Some(1)).unwrap();
+        let tracker = BalanceTracker::new(db.clone(), client, network);
+
+        assert!(!tracker.index_block(1).await.unwrap());
+        assert_eq!(db.get_block_hash(1).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_heal_behind_node() {
+        let db = TestDatabase::new().await;
+        let wallet = Address::ZERO.to_string();
+        db.update_balance(1, wallet.clone(), "BRC20".to_string(), 1)
+            .await;
+        db.set_block_hash(1, "hash1".to_string()).await;
+        let (url, _server) = start_node().await;
+        let client = RpcPool::new(&[url], &RpcOptions::default(), false).unwrap();
+        let network =
+            NetworkConfig::resolve("mainnet", &NetworkSection::default(), Some(1)).unwrap();
+        let tracker = BalanceTracker::new(db.clone(), client, network);
+
+        let mut report = VerificationReport {
+            block_height: 1,
+            block_hash: Some("hash1".to_string()),
+            mismatches: vec![Mismatch {
+                wallet: wallet.clone(),
+                ticker: "BRC20".to_string(),
+                database: 1,
+                node: 5,
+                healed: false,
+            }],
+            ..Default::default()
+        };
+        // The node answers from block 0, its balances aren't the balances at block 1
+        let err = tracker.heal(&mut report).await.unwrap_err();
+        assert!(err.to_string().contains("refusing to heal"));
+        assert!(!report.mismatches[0].healed);
+        assert_eq!(db.get_balance(wallet, "BRC20".to_string()).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_calls_not_pinned() {
+        let db = TestDatabase::new().await;
+        let (url, _server) = start_node().await;
+        let client = RpcPool::new(&[url], &RpcOptions::default(), false).unwrap();
+        let network =
+            NetworkConfig::resolve("mainnet", &NetworkSection::default(), Some(1)).unwrap();
+        let tracker = BalanceTracker::new(db.clone(), client, network);
+
+        let wallet = Address::ZERO.to_string();
+        let err = tracker
+            .check_calls_pinned(wallet, "BRC20".to_string(), "0x1", 2)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("from its latest state"));
+    }
 }
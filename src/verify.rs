@@ -0,0 +1,354 @@
+use std::{collections::BTreeMap, error::Error, path::Path, time::Duration};
+
+use serde_json::{Value, json};
+
+/// Which balances `BalanceTracker::test` compares against the node
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    /// Number of random balances to check, every selected balance if `None`
+    pub sample: Option<u32>,
+    /// Tickers to check, all tickers if empty
+    pub tickers: Vec<String>,
+    /// Wallets to check, all wallets if empty
+    pub wallets: Vec<String>,
+    /// Batches of balance calls in flight at once
+    pub concurrency: usize,
+    /// Height to verify at, balances are reconstructed from history below the indexed height
+    pub height: Option<u64>,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        VerifyOptions {
+            sample: Some(1000),
+            tickers: Vec::new(),
+            wallets: Vec::new(),
+            concurrency: 4,
+            height: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub wallet: String,
+    pub ticker: String,
+    pub database: u128,
+    pub node: u128,
+    /// Whether the database was overwritten with the node's value
+    pub healed: bool,
+}
+
+/// Outcome of a verification run, every mismatch is collected instead of stopping at the first
+#[derive(Debug, Clone, Default)]
+pub struct VerificationReport {
+    pub block_height: u64,
+    /// `None` if nothing was indexed yet
+    pub block_hash: Option<String>,
+    /// Balances checked per ticker
+    pub tickers: BTreeMap<String, u64>,
+    pub mismatches: Vec<Mismatch>,
+    pub duration: Duration,
+}
+
+impl VerificationReport {
+    pub fn checked(&self) -> u64 {
+        self.tickers.values().sum()
+    }
+
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mismatched = |ticker: &str| {
+            self.mismatches
+                .iter()
+                .filter(|mismatch| mismatch.ticker == ticker)
+                .count()
+        };
+        json!({
+            "block_height": self.block_height,
+            "block_hash": self.block_hash,
+            "passed": self.passed(),
+            "checked": self.checked(),
+            "mismatched": self.mismatches.len(),
+            "duration_secs": self.duration.as_secs_f64(),
+            "tickers": self
+                .tickers
+                .iter()
+                .map(|(ticker, checked)| {
+                    (
+                        ticker.clone(),
+                        json!({ "checked": checked, "mismatched": mismatched(ticker) }),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>(),
+            "mismatches": self
+                .mismatches
+                .iter()
+                .map(|mismatch| {
+                    json!({
+                        "wallet": mismatch.wallet,
+                        "ticker": mismatch.ticker,
+                        "database": mismatch.database.to_string(),
+                        "node": mismatch.node.to_string(),
+                        "healed": mismatch.healed,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    fn failed_tickers(&self) -> usize {
+        self.tickers
+            .keys()
+            .filter(|ticker| self.mismatches.iter().any(|m| &m.ticker == *ticker))
+            .count()
+    }
+
+    /// One test case per ticker, failed if any of its balances differ from the node
+    fn junit_suite(&self) -> String {
+        let mut xml = format!(
+            "  <testsuite name=\"block {}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+            self.block_height,
+            self.tickers.len(),
+            self.failed_tickers(),
+            self.duration.as_secs_f64()
+        );
+        xml += "    <properties>\n";
+        xml += &format!(
+            "      <property name=\"block_height\" value=\"{}\"/>\n",
+            self.block_height
+        );
+        xml += &format!(
+            "      <property name=\"block_hash\" value=\"{}\"/>\n",
+            xml_escape(self.block_hash.as_deref().unwrap_or_default())
+        );
+        xml += "    </properties>\n";
+        for (ticker, checked) in &self.tickers {
+            let mismatches: Vec<&Mismatch> = self
+                .mismatches
+                .iter()
+                .filter(|mismatch| &mismatch.ticker == ticker)
+                .collect();
+            let name = xml_escape(ticker);
+            if mismatches.is_empty() {
+                xml += &format!("    <testcase classname=\"balances\" name=\"{}\"/>\n", name);
+                continue;
+            }
+            xml += &format!("    <testcase classname=\"balances\" name=\"{}\">\n", name);
+            xml += &format!(
+                "      <failure type=\"BalanceMismatch\" message=\"{} of {} balances differ from the node\">",
+                mismatches.len(),
+                checked
+            );
+            for mismatch in mismatches {
+                xml += &xml_escape(&format!(
+                    "{}: database {}, node {}{}\n",
+                    mismatch.wallet,
+                    mismatch.database,
+                    mismatch.node,
+                    if mismatch.healed { ", healed" } else { "" }
+                ));
+            }
+            xml += "</failure>\n    </testcase>\n";
+        }
+        xml += "  </testsuite>\n";
+        xml
+    }
+}
+
+/// Heights of `reports` with at least one mismatch
+pub fn mismatched_heights(reports: &[VerificationReport]) -> Vec<u64> {
+    reports
+        .iter()
+        .filter(|report| !report.passed())
+        .map(|report| report.block_height)
+        .collect()
+}
+
+/// Totals over all heights, followed by the report of each height
+pub fn reports_to_json(reports: &[VerificationReport]) -> Value {
+    json!({
+        "passed": reports.iter().all(VerificationReport::passed),
+        "checked": reports.iter().map(VerificationReport::checked).sum::<u64>(),
+        "mismatched": reports.iter().map(|report| report.mismatches.len()).sum::<usize>(),
+        "mismatched_heights": mismatched_heights(reports),
+        "heights": reports.iter().map(VerificationReport::to_json).collect::<Vec<_>>(),
+    })
+}
+
+/// One test suite per height
+pub fn reports_to_junit(reports: &[VerificationReport]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml += &format!(
+        "<testsuites name=\"brc20-prog-balances\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        reports
+            .iter()
+            .map(|report| report.tickers.len())
+            .sum::<usize>(),
+        reports
+            .iter()
+            .map(VerificationReport::failed_tickers)
+            .sum::<usize>(),
+        reports
+            .iter()
+            .map(|report| report.duration)
+            .sum::<Duration>()
+            .as_secs_f64()
+    );
+    for report in reports {
+        xml += &report.junit_suite();
+    }
+    xml += "</testsuites>\n";
+    xml
+}
+
+pub fn write_json(reports: &[VerificationReport], path: &Path) -> Result<(), Box<dyn Error>> {
+    std::fs::write(
+        path,
+        serde_json::to_string_pretty(&reports_to_json(reports))? + "\n",
+    )?;
+    Ok(())
+}
+
+pub fn write_junit(reports: &[VerificationReport], path: &Path) -> Result<(), Box<dyn Error>> {
+    std::fs::write(path, reports_to_junit(reports))?;
+    Ok(())
+}
+
+/// Up to `count` distinct heights between `first_block` and `last_block`, in ascending order
+pub fn random_heights(first_block: u64, last_block: u64, count: u32) -> Vec<u64> {
+    if last_block < first_block {
+        return Vec::new();
+    }
+    let count = (count as u64).min(last_block - first_block + 1) as usize;
+    let mut heights = std::collections::BTreeSet::new();
+    while heights.len() < count {
+        heights.insert(fastrand::u64(first_block..=last_block));
+    }
+    heights.into_iter().collect()
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> VerificationReport {
+        VerificationReport {
+            block_height: 10,
+            block_hash: Some("0xabc".to_string()),
+            tickers: BTreeMap::from([("<a&b>".to_string(), 2), ("ORDI".to_string(), 3)]),
+            mismatches: vec![Mismatch {
+                wallet: "0x01".to_string(),
+                ticker: "<a&b>".to_string(),
+                database: 5,
+                node: u128::MAX,
+                healed: false,
+            }],
+            duration: Duration::from_millis(1500),
+        }
+    }
+
+    #[test]
+    fn test_json_report() {
+        let report = report();
+        assert_eq!(report.checked(), 5);
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_json(),
+            json!({
+                "block_height": 10,
+                "block_hash": "0xabc",
+                "passed": false,
+                "checked": 5,
+                "mismatched": 1,
+                "duration_secs": 1.5,
+                "tickers": {
+                    "<a&b>": { "checked": 2, "mismatched": 1 },
+                    "ORDI": { "checked": 3, "mismatched": 0 },
+                },
+                "mismatches": [{
+                    "wallet": "0x01",
+                    "ticker": "<a&b>",
+                    "database": "5",
+                    "node": u128::MAX.to_string(),
+                    "healed": false,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_reports() {
+        let passed = VerificationReport {
+            block_height: 4,
+            block_hash: Some("0xdef".to_string()),
+            tickers: BTreeMap::from([("ORDI".to_string(), 1)]),
+            duration: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let reports = [passed.clone(), report()];
+        assert_eq!(mismatched_heights(&reports), vec![10]);
+        let json = reports_to_json(&reports);
+        assert_eq!(json["passed"], false);
+        assert_eq!(json["checked"], 6);
+        assert_eq!(json["mismatched"], 1);
+        assert_eq!(json["mismatched_heights"], json!([10]));
+        assert_eq!(json["heights"][0], passed.to_json());
+        assert_eq!(json["heights"][1], report().to_json());
+
+        assert_eq!(
+            reports_to_junit(&reports),
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="brc20-prog-balances" tests="3" failures="1" time="2.000">
+  <testsuite name="block 4" tests="1" failures="0" time="0.500">
+    <properties>
+      <property name="block_height" value="4"/>
+      <property name="block_hash" value="0xdef"/>
+    </properties>
+    <testcase classname="balances" name="ORDI"/>
+  </testsuite>
+  <testsuite name="block 10" tests="2" failures="1" time="1.500">
+    <properties>
+      <property name="block_height" value="10"/>
+      <property name="block_hash" value="0xabc"/>
+    </properties>
+    <testcase classname="balances" name="&lt;a&amp;b&gt;">
+      <failure type="BalanceMismatch" message="1 of 2 balances differ from the node">0x01: database 5, node {}
+</failure>
+    </testcase>
+    <testcase classname="balances" name="ORDI"/>
+  </testsuite>
+</testsuites>
+"#,
+                u128::MAX
+            )
+        );
+    }
+
+    #[test]
+    fn test_random_heights() {
+        assert_eq!(random_heights(5, 4, 3), Vec::<u64>::new());
+        assert_eq!(random_heights(5, 7, 10), vec![5, 6, 7]);
+        let heights = random_heights(100, 1_000_000, 20);
+        assert_eq!(heights.len(), 20);
+        assert!(heights.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(
+            heights
+                .iter()
+                .all(|height| (100..=1_000_000).contains(height))
+        );
+    }
+}